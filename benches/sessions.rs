@@ -0,0 +1,125 @@
+//! Per-session overhead of the websocket driver.
+//!
+//! Run with `cargo bench --bench sessions`. Client and server share the process, so the memory
+//! figure includes the client side of every connection, compare it between runs of this bench
+//! rather than as an absolute.
+use awc::Client;
+use axum_websockets::{configuration::get_configuration, Application};
+use futures::{SinkExt, StreamExt};
+use std::time::{Duration, Instant};
+
+const IDLE_CONNECTIONS: usize = 2_000;
+const MESSAGES: usize = 20_000;
+
+fn main() {
+    actix_rt::System::new().block_on(async {
+        let address = spawn_app();
+        idle_connections(&address).await;
+        throughput(&address, "app ping", r#"{"type": "ping"}"#).await;
+        throughput(
+            &address,
+            "get_files",
+            r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples"}"#,
+        )
+        .await;
+    });
+}
+
+fn spawn_app() -> String {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.port = 0;
+    configuration.connection_limits.max_sessions = usize::MAX;
+    configuration.connection_limits.max_sessions_per_ip = usize::MAX;
+    configuration.websocket.client_timeout = Duration::from_secs(600);
+    let application = Application::build(configuration).expect("Failed to build application.");
+    let address = format!("http://127.0.0.1:{}/ws", application.port());
+    tokio::spawn(application.run_until_stopped());
+    address
+}
+
+/// Resident memory of the process, in bytes.
+fn rss() -> usize {
+    let statm = std::fs::read_to_string("/proc/self/statm").expect("Failed to read statm.");
+    let pages = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse::<usize>().ok())
+        .expect("Failed to parse statm.");
+    pages * 4096
+}
+
+fn alive_tasks() -> usize {
+    tokio::runtime::Handle::current()
+        .metrics()
+        .num_alive_tasks()
+}
+
+async fn idle_connections(address: &str) {
+    // Warm up allocator and server
+    drop(connect(address).await);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let (rss_before, tasks_before) = (rss(), alive_tasks());
+
+    let mut connections = Vec::with_capacity(IDLE_CONNECTIONS);
+    for _ in 0..IDLE_CONNECTIONS {
+        connections.push(connect(address).await);
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let (rss_after, tasks_after) = (rss(), alive_tasks());
+
+    println!(
+        "idle connections: {} connections, {:.1} KiB and {:.1} tasks per connection",
+        IDLE_CONNECTIONS,
+        (rss_after.saturating_sub(rss_before)) as f64 / IDLE_CONNECTIONS as f64 / 1024.0,
+        (tasks_after.saturating_sub(tasks_before)) as f64 / IDLE_CONNECTIONS as f64,
+    );
+}
+
+type Connection = actix_codec::Framed<awc::BoxedSocket, awc::ws::Codec>;
+
+/// Connects and waits for the server hello, so the session is fully set up.
+async fn connect(address: &str) -> Connection {
+    let (_response, mut connection) = Client::new()
+        .ws(address)
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+    loop {
+        if let Some(Ok(awc::ws::Frame::Text(_))) = connection.next().await {
+            return connection;
+        }
+    }
+}
+
+/// Sends `MESSAGES` copies of `message` on a connection while reading the replies.
+async fn throughput(address: &str, name: &str, message: &str) {
+    let (mut sink, mut stream) = connect(address).await.split();
+    let start = Instant::now();
+    let send = async {
+        for _ in 0..MESSAGES {
+            sink.feed(awc::ws::Message::Text(message.to_string().into()))
+                .await
+                .expect("Failed to send message.");
+        }
+        sink.flush().await.expect("Failed to send message.");
+    };
+    let receive = async {
+        let mut received = 0;
+        while received < MESSAGES {
+            match stream.next().await {
+                Some(Ok(awc::ws::Frame::Text(_))) => received += 1,
+                Some(Ok(_)) => {}
+                other => panic!("Connection failed: {:?}", other),
+            }
+        }
+    };
+    futures::join!(send, receive);
+    let elapsed = start.elapsed();
+    println!(
+        "{}: {} messages in {:.2?}, {:.0} messages/s",
+        name,
+        MESSAGES,
+        elapsed,
+        MESSAGES as f64 / elapsed.as_secs_f64()
+    );
+}
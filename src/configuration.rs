@@ -1,7 +1,10 @@
+use crate::subsystems::WebsocketSystem;
 use serde::Deserialize;
 use serde_with::{serde_as, DurationMilliSeconds};
 use std::{
+    collections::HashMap,
     convert::{TryFrom, TryInto},
+    path::PathBuf,
     time::Duration,
 };
 
@@ -12,6 +15,12 @@ pub struct Settings {
     /// Enable tokio-console
     pub console: bool,
     pub websocket: WebsocketSettings,
+    pub identity: IdentitySettings,
+    pub connection_limits: ConnectionLimitSettings,
+    pub subsystems: SubsystemSettings,
+    pub cache: CacheSettings,
+    pub idempotency: IdempotencySettings,
+    pub fair_queue: FairQueueSettings,
 }
 
 #[serde_as]
@@ -23,6 +32,218 @@ pub struct WebsocketSettings {
     /// In milliseconds
     #[serde_as(as = "DurationMilliSeconds<u64>")]
     pub client_timeout: Duration,
+    /// Lower bound of the heartbeat interval a client may negotiate, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub min_heartbeat_interval: Duration,
+    /// Upper bound of the heartbeat interval a client may negotiate, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub max_heartbeat_interval: Duration,
+    /// Sessions without client requests for this long are closed, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub idle_timeout: Duration,
+    /// How long before closing an idle session it gets a warning, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub idle_warning: Duration,
+    /// Subsystem tasks of a session stop after being unused for this long, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub worker_idle_timeout: Duration,
+    /// Maximum size of client messages, in bytes. Larger messages get a structured error, as
+    /// long as they fit in `max_inbound_frame_size`
+    pub max_inbound_message_size: usize,
+    /// Maximum size of client frames and messages accepted by the transport, in bytes. The
+    /// connection is closed on larger ones, without reading them whole
+    pub max_inbound_frame_size: usize,
+    /// Maximum size of messages sent to the client, in bytes
+    pub max_outbound_message_size: usize,
+    /// Maximum number of messages in a batch frame
+    pub max_batch_size: usize,
+    /// Minimum time between progress reports of a task, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub progress_interval: Duration,
+    /// Default time limit for a task, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub task_timeout: Duration,
+    /// Per subsystem overrides of `task_timeout`, in milliseconds
+    #[serde_as(as = "HashMap<_, DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub subsystem_task_timeouts: HashMap<WebsocketSystem, Duration>,
+    /// How long the server waits for sessions to close when shutting down, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub shutdown_timeout: Duration,
+}
+
+impl WebsocketSettings {
+    /// Limit given to the websocket transport, never below `max_inbound_message_size`.
+    pub fn transport_message_limit(&self) -> usize {
+        self.max_inbound_frame_size
+            .max(self.max_inbound_message_size)
+    }
+
+    pub fn task_timeout(&self, system: WebsocketSystem) -> Duration {
+        self.subsystem_task_timeouts
+            .get(&system)
+            .copied()
+            .unwrap_or(self.task_timeout)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdentitySettings {
+    /// Header set by the authenticating proxy with the client identity
+    pub header: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConnectionLimitSettings {
+    /// Maximum number of concurrent sessions
+    pub max_sessions: usize,
+    /// Maximum number of concurrent sessions from the same peer IP
+    pub max_sessions_per_ip: usize,
+    /// Maximum number of concurrent sessions for the same identity
+    pub max_sessions_per_identity: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubsystemSettings {
+    pub python_repo: PythonRepoSettings,
+    pub pc_usage: PcUsageSettings,
+    pub session: SessionSettings,
+    pub jobs: JobSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PythonRepoSettings {
+    /// Number of files sent on each chunk of a streamed `get_files` result
+    pub chunk_size: usize,
+    /// Filesystem walks run at once on the blocking pool, the others wait for their turn
+    pub blocking_walks: usize,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct PcUsageSettings {
+    /// Time the cpu load is measured over, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub sample_duration: Duration,
+    /// A cpu load sample is shared by every session for this long, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub sample_ttl: Duration,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheSettings {
+    /// Maximum number of cached results, across every task
+    pub max_entries: usize,
+    /// How long results are cached per subsystem and task, in milliseconds. Tasks that are not
+    /// listed are never cached
+    #[serde_as(as = "HashMap<_, HashMap<_, DurationMilliSeconds<u64>>>")]
+    #[serde(default)]
+    pub ttl: HashMap<WebsocketSystem, HashMap<String, Duration>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FairQueueSettings {
+    /// Number of tasks of each cost class allowed to run at once, across the server
+    #[serde(default)]
+    pub classes: HashMap<String, usize>,
+    /// Cost of the limited tasks, per subsystem and task. Tasks that are not listed run
+    /// without waiting
+    #[serde(default)]
+    pub tasks: HashMap<WebsocketSystem, HashMap<String, TaskCost>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaskCost {
+    pub class: String,
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+/// Order in which waiting tasks of a cost class get to run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdempotencySettings {
+    /// How long results are replayed for their idempotency key, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub window: Duration,
+    /// Maximum number of remembered results, across every identity
+    pub max_entries: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionSettings {
+    /// Maximum number of keys in the store of a session
+    pub max_keys: usize,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobSettings {
+    /// Finished jobs are kept for this long, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub retention: Duration,
+    /// Time limit for a job, in milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub timeout: Duration,
+    /// Maximum number of running and retained jobs
+    pub max_jobs: usize,
+    /// File the jobs are recorded in, jobs are only kept in memory without one
+    #[serde(default)]
+    pub store: Option<PathBuf>,
+    /// What to do with the jobs found unfinished on startup, per subsystem and task. Defaults
+    /// to `fail`
+    #[serde(default)]
+    pub restart_policies: HashMap<WebsocketSystem, HashMap<String, RestartPolicy>>,
+    /// Tasks run as jobs on a schedule, whether or not a session is open
+    #[serde(default)]
+    pub schedules: Vec<ScheduleSettings>,
+}
+
+/// A task run on a schedule, either every `every` milliseconds or at the times matched by the
+/// `cron` expression, in UTC. Cron expressions start with the seconds, as in `0 0 3 * * *`.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleSettings {
+    pub name: String,
+    pub system: WebsocketSystem,
+    pub task: String,
+    #[serde(default)]
+    pub payload: serde_json::Value,
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub every: Option<Duration>,
+    #[serde(default)]
+    pub cron: Option<String>,
+}
+
+impl JobSettings {
+    pub fn restart_policy(&self, system: WebsocketSystem, task: &str) -> RestartPolicy {
+        self.restart_policies
+            .get(&system)
+            .and_then(|tasks| tasks.get(task))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// Run the job again
+    Requeue,
+    /// Mark the job as failed
+    #[default]
+    Fail,
 }
 
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
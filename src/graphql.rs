@@ -0,0 +1,327 @@
+//! GraphQL endpoint speaking the `graphql-transport-ws` protocol.
+//!
+//! Subsystem tasks are exposed as query fields and periodic tasks as subscriptions. They run
+//! through the same task services as the websocket sessions, layers included.
+use crate::{
+    configuration::WebsocketSettings,
+    limits::ConnectionGuard,
+    message::{ErrorPayload, Protocol, ResultMessage},
+    progress::ProgressReporter,
+    service::{TaskRequest, TaskResponse},
+    session::{SessionContext, SessionStore},
+    shutdown::{Shutdown, SHUTDOWN_CLOSE_CODE},
+    subsystems::{SessionInfo, SessionStates, SubsystemRegistry, TaskContext, WebsocketSystem},
+};
+use async_graphql::{
+    http::{WebSocket as GraphQLWebSocket, WsMessage},
+    Context, EmptyMutation, ErrorExtensions, Json, Object, Schema, Subscription,
+};
+use axum::{
+    async_trait,
+    extract::{
+        ws::{CloseFrame, Message, WebSocket},
+        FromRequest, RequestParts,
+    },
+    http::{header::SEC_WEBSOCKET_PROTOCOL, StatusCode},
+};
+use futures::{FutureExt, SinkExt, Stream, StreamExt};
+use std::{panic::AssertUnwindSafe, str::FromStr, sync::Arc, time::Duration};
+use tokio::time::Instant;
+use tower::ServiceExt;
+
+pub use async_graphql::http::{
+    WebSocketProtocols as GraphQLProtocol, ALL_WEBSOCKET_PROTOCOLS as SUBPROTOCOLS,
+};
+
+pub type GraphQLSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+/// Shortest interval accepted for subscriptions.
+const MIN_SUBSCRIPTION_INTERVAL: Duration = Duration::from_millis(50);
+
+pub fn build_schema(
+    settings: Arc<WebsocketSettings>,
+    subsystems: SubsystemRegistry,
+) -> GraphQLSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(settings)
+        .data(subsystems)
+        .finish()
+}
+
+/// GraphQL websocket protocol requested by the client, upgrades without one are rejected.
+pub struct GraphQLSubprotocol(pub GraphQLProtocol);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for GraphQLSubprotocol {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        req.headers()
+            .and_then(|headers| headers.get(SEC_WEBSOCKET_PROTOCOL))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|protocols| {
+                protocols
+                    .split(',')
+                    .find_map(|protocol| GraphQLProtocol::from_str(protocol.trim()).ok())
+            })
+            .map(Self)
+            .ok_or((
+                StatusCode::BAD_REQUEST,
+                "Missing graphql-transport-ws subprotocol.",
+            ))
+    }
+}
+
+/// Maps a task result to a GraphQL result, structured errors keep their code as an extension.
+fn into_graphql(result: ResultMessage) -> async_graphql::Result<Json<serde_json::Value>> {
+    if result.success {
+        return Ok(Json(result.payload));
+    }
+    let error = match serde_json::from_value::<ErrorPayload>(result.payload.clone()) {
+        Ok(error) => {
+            let code = serde_json::to_value(error.code)
+                .ok()
+                .and_then(|code| code.as_str().map(String::from))
+                .unwrap_or_default();
+            async_graphql::Error::new(error.message)
+                .extend_with(|_, extensions| extensions.set("code", code))
+        }
+        Err(_) => match result.payload {
+            serde_json::Value::String(e) => async_graphql::Error::new(e),
+            payload => async_graphql::Error::new(payload.to_string()),
+        },
+    };
+    Err(error)
+}
+
+/// The GraphQL session a query or subscription comes from, given as connection data.
+#[derive(Clone)]
+struct GraphQLSession {
+    context: SessionContext,
+    states: SessionStates,
+}
+
+/// Runs a task through the task service of its subsystem, as websocket sessions do.
+async fn run_task(
+    settings: &WebsocketSettings,
+    subsystems: &SubsystemRegistry,
+    session: &GraphQLSession,
+    system: WebsocketSystem,
+    task: &str,
+    payload: serde_json::Value,
+) -> async_graphql::Result<Json<serde_json::Value>> {
+    let request = TaskRequest {
+        system,
+        task: task.to_string(),
+        payload,
+        id: None,
+        stream: false,
+        no_cache: false,
+        idempotency_key: None,
+        ctx: TaskContext {
+            progress: ProgressReporter::disabled(system),
+            deadline: Instant::now() + settings.task_timeout(system),
+            session: Some(session.context.clone()),
+        },
+        session: session.context.info.clone(),
+    };
+    let result = match subsystems
+        .service(system, &session.states)
+        .oneshot(request)
+        .await
+    {
+        Ok(TaskResponse::Result(result)) => result,
+        Ok(TaskResponse::Stream(_)) => {
+            ResultMessage::from_error("GraphQL fields cannot stream.", Some(system))
+        }
+        Err(e) => ResultMessage::from_error(e, Some(system)),
+    };
+    into_graphql(result)
+}
+
+/// Runs a task for the session of `ctx`.
+async fn run_query(
+    ctx: &Context<'_>,
+    system: WebsocketSystem,
+    task: &str,
+    payload: serde_json::Value,
+) -> async_graphql::Result<Json<serde_json::Value>> {
+    let settings = ctx.data_unchecked::<Arc<WebsocketSettings>>();
+    let subsystems = ctx.data_unchecked::<SubsystemRegistry>();
+    let session = ctx.data::<GraphQLSession>()?;
+    run_task(settings, subsystems, session, system, task, payload).await
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn pc_usage(&self) -> PcUsageQuery {
+        PcUsageQuery
+    }
+
+    async fn python_repo(&self) -> PythonRepoQuery {
+        PythonRepoQuery
+    }
+}
+
+pub struct PcUsageQuery;
+
+#[Object]
+impl PcUsageQuery {
+    /// Load of every cpu, sampled over a short period.
+    async fn cpu_load(&self, ctx: &Context<'_>) -> async_graphql::Result<Json<serde_json::Value>> {
+        run_query(
+            ctx,
+            WebsocketSystem::PcUsage,
+            "cpu_load",
+            serde_json::Value::Null,
+        )
+        .await
+    }
+}
+
+pub struct PythonRepoQuery;
+
+#[Object]
+impl PythonRepoQuery {
+    /// Python files found under `path`.
+    async fn get_files(
+        &self,
+        ctx: &Context<'_>,
+        path: String,
+    ) -> async_graphql::Result<Json<serde_json::Value>> {
+        run_query(
+            ctx,
+            WebsocketSystem::PythonRepo,
+            "get_files",
+            serde_json::Value::String(path),
+        )
+        .await
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Samples the cpu load every `interval_ms` milliseconds.
+    async fn cpu_load(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 1000)] interval_ms: u64,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<Json<serde_json::Value>>>>
+    {
+        let settings = ctx.data_unchecked::<Arc<WebsocketSettings>>().clone();
+        let subsystems = ctx.data_unchecked::<SubsystemRegistry>().clone();
+        let session = ctx.data::<GraphQLSession>()?.clone();
+        let interval = Duration::from_millis(interval_ms).max(MIN_SUBSCRIPTION_INTERVAL);
+        let interval = tokio::time::interval(interval);
+        Ok(futures::stream::unfold(interval, move |mut interval| {
+            let settings = settings.clone();
+            let subsystems = subsystems.clone();
+            let session = session.clone();
+            async move {
+                interval.tick().await;
+                let result = run_task(
+                    &settings,
+                    &subsystems,
+                    &session,
+                    WebsocketSystem::PcUsage,
+                    "cpu_load",
+                    serde_json::Value::Null,
+                )
+                .await;
+                Some((result, interval))
+            }
+        }))
+    }
+}
+
+/// Runs a GraphQL session, with the same lifecycle hooks and shutdown handling as the
+/// websocket sessions.
+#[tracing::instrument(
+    name = "Handling GraphQL websocket",
+    skip(socket, schema, subsystems, connection, shutdown),
+    fields(peer_ip = %connection.ip(), identity = ?connection.identity())
+)]
+pub async fn handle_graphql_socket(
+    socket: WebSocket,
+    schema: GraphQLSchema,
+    subsystems: SubsystemRegistry,
+    protocol: GraphQLProtocol,
+    connection: ConnectionGuard,
+    shutdown: Shutdown,
+) {
+    // Held until the end hooks ran, so the server waits for them when shutting down
+    let _session = shutdown.session();
+    let info = Arc::new(SessionInfo::new(&connection, Protocol::GraphQL));
+    let states = SessionStates::default();
+    subsystems.session_started(&info, &states).await;
+    let store = SessionStore::new(subsystems.session.max_keys());
+    let session = GraphQLSession {
+        context: SessionContext::new(info.clone(), store),
+        states: states.clone(),
+    };
+    // A panicking session must still release its resources
+    let bridge = bridge(socket, schema, protocol, session, shutdown);
+    if AssertUnwindSafe(bridge).catch_unwind().await.is_err() {
+        tracing::error!("GraphQL session panicked.");
+    }
+    subsystems.session_ended(&info, &states).await;
+    // Releases the session slot
+    drop(connection);
+}
+
+/// Bridges the websocket with the GraphQL protocol implementation, until either side closes
+/// or the server shuts down.
+async fn bridge(
+    socket: WebSocket,
+    schema: GraphQLSchema,
+    protocol: GraphQLProtocol,
+    session: GraphQLSession,
+    shutdown: Shutdown,
+) {
+    let (mut socket_sender, socket_receiver) = socket.split();
+    let input = socket_receiver
+        .take_while(|msg| futures::future::ready(msg.is_ok()))
+        .filter_map(|msg| {
+            futures::future::ready(match msg {
+                Ok(Message::Text(text)) => Some(text.into_bytes()),
+                Ok(Message::Binary(bytes)) => Some(bytes),
+                _ => None,
+            })
+        });
+    let mut data = async_graphql::Data::default();
+    data.insert(session);
+    let mut output = GraphQLWebSocket::new(schema, input, protocol).connection_data(data);
+    loop {
+        let msg = tokio::select! {
+            msg = output.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = shutdown.triggered() => {
+                tracing::info!("Server shutting down, closing the GraphQL websocket.");
+                WsMessage::Close(SHUTDOWN_CLOSE_CODE, "Server shutting down.".to_string())
+            }
+        };
+        let (msg, closing) = match msg {
+            WsMessage::Text(text) => (Message::Text(text), false),
+            WsMessage::Close(code, reason) => (
+                Message::Close(Some(CloseFrame {
+                    code,
+                    reason: reason.into(),
+                })),
+                true,
+            ),
+        };
+        if let Err(e) = socket_sender.send(msg).await {
+            tracing::info!("Failed to send GraphQL message: {:?}", e);
+            break;
+        }
+        if closing {
+            break;
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Session handshake.
+//!
+//! The server greets native clients with a [`ServerHello`] right after the upgrade. Clients
+//! may answer with a [`ClientHello`] to negotiate the session parameters, which the server
+//! confirms with a `welcome` message holding the [`Negotiated`] values.
+use crate::{configuration::WebsocketSettings, subsystems::WebsocketSystem};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
+use std::time::Duration;
+
+/// Versions of the native protocol spoken by this server, newest first.
+pub const PROTOCOL_VERSIONS: &[u32] = &[1];
+/// Encodings of the messages, by preference.
+pub const ENCODINGS: &[&str] = &["json"];
+/// Compression of the messages, by preference.
+pub const COMPRESSION: &[&str] = &["none"];
+
+/// Handshake messages sent to the client.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HandshakeMessage {
+    Hello(ServerHello),
+    Welcome(Negotiated),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerHello {
+    pub server: ServerInfo,
+    pub protocol_versions: Vec<u32>,
+    pub systems: Vec<WebsocketSystem>,
+    pub encodings: Vec<String>,
+    pub compression: Vec<String>,
+    pub heartbeat: HeartbeatBounds,
+}
+
+/// Heartbeat timing of the session and the range a client may ask for.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HeartbeatBounds {
+    /// In milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub interval: Duration,
+    /// In milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub client_timeout: Duration,
+    /// In milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub min_interval: Duration,
+    /// In milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub max_interval: Duration,
+}
+
+impl ServerHello {
+    pub fn new(settings: &WebsocketSettings) -> Self {
+        Self {
+            server: ServerInfo {
+                name: env!("CARGO_PKG_NAME").into(),
+                version: env!("CARGO_PKG_VERSION").into(),
+            },
+            protocol_versions: PROTOCOL_VERSIONS.to_vec(),
+            systems: WebsocketSystem::ALL.to_vec(),
+            encodings: ENCODINGS.iter().map(|s| s.to_string()).collect(),
+            compression: COMPRESSION.iter().map(|s| s.to_string()).collect(),
+            heartbeat: HeartbeatBounds {
+                interval: settings.heartbeat_interval,
+                client_timeout: settings.client_timeout,
+                min_interval: settings.min_heartbeat_interval,
+                max_interval: settings.max_heartbeat_interval,
+            },
+        }
+    }
+}
+
+/// Session parameters proposed by the client, every field is optional.
+#[serde_as]
+#[derive(Debug, Default, Deserialize)]
+pub struct ClientHello {
+    /// Accepted protocol versions, by preference
+    #[serde(default)]
+    pub protocol_versions: Vec<u32>,
+    /// Accepted encodings, by preference
+    #[serde(default)]
+    pub encodings: Vec<String>,
+    /// Accepted compression, by preference
+    #[serde(default)]
+    pub compression: Vec<String>,
+    /// Preferred heartbeat interval, in milliseconds
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub heartbeat_interval: Option<Duration>,
+}
+
+/// Parameters in use by a session.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Negotiated {
+    pub protocol_version: u32,
+    pub encoding: String,
+    pub compression: String,
+    /// In milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub heartbeat_interval: Duration,
+    /// In milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub client_timeout: Duration,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum HandshakeError {
+    #[error("None of the protocol versions {0:?} is supported.")]
+    UnsupportedProtocolVersion(Vec<u32>),
+    #[error("None of the encodings {0:?} is supported.")]
+    UnsupportedEncoding(Vec<String>),
+    #[error("None of the compression methods {0:?} is supported.")]
+    UnsupportedCompression(Vec<String>),
+    #[error("Handshake already completed.")]
+    AlreadyNegotiated,
+}
+
+impl Negotiated {
+    /// Parameters of a session whose client did not send a hello.
+    pub fn defaults(settings: &WebsocketSettings) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSIONS[0],
+            encoding: ENCODINGS[0].into(),
+            compression: COMPRESSION[0].into(),
+            heartbeat_interval: settings.heartbeat_interval,
+            client_timeout: settings.client_timeout,
+        }
+    }
+
+    /// Picks the first supported option of every client preference. The heartbeat interval is
+    /// clamped to the configured bounds and the client timeout keeps its configured ratio.
+    pub fn negotiate(
+        settings: &WebsocketSettings,
+        hello: ClientHello,
+    ) -> Result<Self, HandshakeError> {
+        let defaults = Self::defaults(settings);
+        let protocol_version = if hello.protocol_versions.is_empty() {
+            defaults.protocol_version
+        } else {
+            hello
+                .protocol_versions
+                .iter()
+                .copied()
+                .find(|version| PROTOCOL_VERSIONS.contains(version))
+                .ok_or(HandshakeError::UnsupportedProtocolVersion(
+                    hello.protocol_versions,
+                ))?
+        };
+        let encoding = pick(hello.encodings, ENCODINGS, defaults.encoding)
+            .map_err(HandshakeError::UnsupportedEncoding)?;
+        let compression = pick(hello.compression, COMPRESSION, defaults.compression)
+            .map_err(HandshakeError::UnsupportedCompression)?;
+        let (heartbeat_interval, client_timeout) = match hello.heartbeat_interval {
+            None => (defaults.heartbeat_interval, defaults.client_timeout),
+            Some(interval) => {
+                let interval = interval
+                    .max(settings.min_heartbeat_interval)
+                    .min(settings.max_heartbeat_interval);
+                let ratio = settings.client_timeout.as_secs_f64()
+                    / settings.heartbeat_interval.as_secs_f64();
+                (interval, interval.mul_f64(ratio))
+            }
+        };
+        Ok(Self {
+            protocol_version,
+            encoding,
+            compression,
+            heartbeat_interval,
+            client_timeout,
+        })
+    }
+}
+
+/// First of `requested` that is `supported`, `default` if nothing was requested.
+fn pick(
+    requested: Vec<String>,
+    supported: &[&str],
+    default: String,
+) -> Result<String, Vec<String>> {
+    if requested.is_empty() {
+        return Ok(default);
+    }
+    match requested
+        .iter()
+        .find(|option| supported.contains(&option.as_str()))
+    {
+        Some(option) => Ok(option.clone()),
+        None => Err(requested),
+    }
+}
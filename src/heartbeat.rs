@@ -0,0 +1,82 @@
+//! Application level heartbeat, for clients that cannot use websocket ping frames
+//! (e.g. browsers), and round-trip time statistics.
+use crate::message::RequestId;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSecondsWithFrac};
+use std::{
+    convert::TryInto,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// `{"type": "ping"}` message from client, refreshes the session heartbeat.
+#[derive(Debug, Deserialize)]
+pub struct ClientPing {
+    #[serde(default)]
+    pub id: Option<RequestId>,
+}
+
+/// Reply to a [`ClientPing`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "pong")]
+pub struct PongMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    /// Milliseconds since the unix epoch
+    pub server_time: u128,
+    pub latency: LatencyStats,
+}
+
+impl PongMessage {
+    pub fn new(id: Option<RequestId>, latency: LatencyStats) -> Self {
+        let server_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Self {
+            id,
+            server_time,
+            latency,
+        }
+    }
+}
+
+/// Round-trip times measured from the pongs of the server pings.
+#[serde_as]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LatencyStats {
+    pub samples: u64,
+    /// In milliseconds
+    #[serde_as(as = "Option<DurationMilliSecondsWithFrac<f64>>")]
+    pub last: Option<Duration>,
+    /// In milliseconds
+    #[serde_as(as = "Option<DurationMilliSecondsWithFrac<f64>>")]
+    pub min: Option<Duration>,
+    /// In milliseconds
+    #[serde_as(as = "Option<DurationMilliSecondsWithFrac<f64>>")]
+    pub max: Option<Duration>,
+    /// In milliseconds
+    #[serde_as(as = "Option<DurationMilliSecondsWithFrac<f64>>")]
+    pub mean: Option<Duration>,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, rtt: Duration) {
+        let total = self.mean.unwrap_or_default() * self.samples as u32 + rtt;
+        self.samples += 1;
+        self.last = Some(rtt);
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+        self.mean = Some(total / self.samples as u32);
+    }
+}
+
+/// Payload of a server ping, the time it was sent relative to `start`.
+pub fn ping_payload(start: std::time::Instant) -> Vec<u8> {
+    (start.elapsed().as_micros() as u64).to_be_bytes().to_vec()
+}
+
+/// Round-trip time of a pong whose payload came from [`ping_payload`].
+pub fn pong_rtt(start: std::time::Instant, payload: &[u8]) -> Option<Duration> {
+    let sent = u64::from_be_bytes(payload.try_into().ok()?);
+    start.elapsed().checked_sub(Duration::from_micros(sent))
+}
@@ -0,0 +1,27 @@
+use crate::configuration::IdentitySettings;
+use axum::{
+    async_trait,
+    extract::{FromRequest, RequestParts},
+};
+use std::{convert::Infallible, sync::Arc};
+
+/// Client identity, as forwarded by the authenticating proxy in the
+/// configured header. `None` for anonymous clients.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity(pub Option<String>);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Identity {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let identity = req
+            .extensions()
+            .and_then(|extensions| extensions.get::<Arc<IdentitySettings>>())
+            .and_then(|settings| req.headers()?.get(settings.header.as_str()))
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty())
+            .map(String::from);
+        Ok(Self(identity))
+    }
+}
@@ -0,0 +1,83 @@
+//! Idle detection, based on client requests rather than heartbeats.
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Close code sent to sessions closed for being idle.
+pub const IDLE_CLOSE_CODE: u16 = 4000;
+
+/// Tracks the last client request and the tasks still running for a session.
+#[derive(Clone)]
+pub struct IdleTracker {
+    last_activity: Arc<Mutex<Instant>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Default for IdleTracker {
+    fn default() -> Self {
+        Self {
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl IdleTracker {
+    /// Records a meaningful client message.
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Marks a task as running until the returned guard is dropped.
+    pub fn track(&self) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            tracker: self.clone(),
+        }
+    }
+
+    /// Time since the last activity, `None` while tasks are running.
+    pub fn idle_for(&self) -> Option<Duration> {
+        if self.in_flight.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        Some(self.last_activity.lock().unwrap().elapsed())
+    }
+}
+
+/// A running task, the idle clock restarts when it is dropped.
+#[derive(Debug)]
+pub struct InFlightGuard {
+    tracker: IdleTracker,
+}
+
+impl std::fmt::Debug for IdleTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdleTracker")
+            .field("in_flight", &self.in_flight.load(Ordering::SeqCst))
+            .finish()
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.tracker.touch();
+        self.tracker.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// `{"type": "idle_warning"}` message, sent before closing an idle session.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "idle_warning")]
+pub struct IdleWarning {
+    /// In milliseconds
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub closes_in: Duration,
+}
@@ -0,0 +1,527 @@
+//! Background jobs, tasks that keep running after the session that submitted them ends.
+pub mod scheduler;
+pub mod store;
+
+use self::store::{JobRecord, JobStore, StoreWriter, StoredRequest};
+use crate::{
+    configuration::{JobSettings, RestartPolicy},
+    message::ResultMessage,
+    service::{TaskRequest, TaskResponse, TaskService},
+    subsystems::{SessionInfo, TaskContext, WebsocketSystem},
+    telemetry::tokio_spawn,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::broadcast, task::JoinHandle};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobError {
+    #[error("Job {0} not found.")]
+    NotFound(Uuid),
+    #[error("Job {0} is still running.")]
+    Running(Uuid),
+    #[error("Too many jobs, the limit is {0}.")]
+    TooManyJobs(usize),
+    #[error("Job {0} was submitted by a schedule and cannot be cancelled.")]
+    Scheduled(Uuid),
+    #[error("Job {0} can only be cancelled by the session that submitted it.")]
+    NotSubmitter(Uuid),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// Public view of a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub system: WebsocketSystem,
+    pub task: String,
+    pub status: JobStatus,
+    /// Milliseconds since the Unix epoch
+    pub submitted_at: u64,
+    /// Milliseconds the job ran for, once finished
+    pub duration: Option<u64>,
+    /// Name of the schedule that submitted the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+}
+
+/// A job that just finished, sent to the subscribers of the manager.
+#[derive(Debug, Clone)]
+pub struct FinishedJob {
+    pub info: JobInfo,
+    /// `None` if the job was cancelled
+    pub result: Option<ResultMessage>,
+}
+
+/// Store records left over by purged jobs before the store is compacted.
+const COMPACT_THRESHOLD: usize = 256;
+/// Finished jobs buffered for slow subscribers.
+const EVENTS_CAPACITY: usize = 64;
+
+struct Job {
+    info: JobInfo,
+    /// Session that submitted the job, only sessions with the same identity may access it
+    session: Arc<SessionInfo>,
+    request: StoredRequest,
+    started: Instant,
+    finished: Option<Instant>,
+    /// Milliseconds since the Unix epoch, once finished
+    finished_at: u64,
+    result: Option<ResultMessage>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Job {
+    fn finish(&mut self, status: JobStatus, result: Option<ResultMessage>) {
+        let finished = Instant::now();
+        self.info.status = status;
+        self.info.duration = Some(finished.duration_since(self.started).as_millis() as u64);
+        self.finished = Some(finished);
+        self.finished_at = now_millis();
+        self.result = result;
+        self.handle = None;
+    }
+
+    fn submitted_record(&self) -> JobRecord {
+        JobRecord::Submitted {
+            info: JobInfo {
+                status: JobStatus::Running,
+                duration: None,
+                ..self.info.clone()
+            },
+            session: (*self.session).clone(),
+            request: self.request.clone(),
+        }
+    }
+
+    /// `None` while the job is running.
+    fn finished_record(&self) -> Option<JobRecord> {
+        self.finished.map(|_| JobRecord::Finished {
+            id: self.info.id.clone(),
+            status: self.info.status,
+            duration: self.info.duration,
+            finished_at: self.finished_at,
+            result: self.result.clone(),
+        })
+    }
+
+    /// Request running the job again.
+    fn task_request(&self) -> TaskRequest {
+        let system = self.info.system;
+        TaskRequest {
+            system,
+            task: self.info.task.clone(),
+            payload: self.request.payload.clone(),
+            id: self.request.id.clone(),
+            stream: false,
+            no_cache: self.request.no_cache,
+            idempotency_key: None,
+            ctx: TaskContext::detached(system, self.request.timeout),
+            session: self.session.clone(),
+        }
+    }
+}
+
+/// Runs background jobs and keeps finished ones for `retention`.
+///
+/// Once `open_store` is called, jobs are also recorded in the store file of the settings, so
+/// they are still known after a restart.
+#[derive(Clone)]
+pub struct JobManager {
+    settings: JobSettings,
+    jobs: Arc<Mutex<HashMap<Uuid, Job>>>,
+    /// Always locked after `jobs`, only to hand records to the writer thread
+    store: Arc<Mutex<Option<StoreWriter>>>,
+    finished: broadcast::Sender<FinishedJob>,
+}
+
+impl JobManager {
+    pub fn new(settings: JobSettings) -> Self {
+        Self {
+            settings,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            store: Arc::new(Mutex::new(None)),
+            finished: broadcast::channel(EVENTS_CAPACITY).0,
+        }
+    }
+
+    /// Restores the jobs of the store file and records the new ones in it. Finished jobs are
+    /// kept for the rest of their retention, unfinished ones are run again or marked as failed
+    /// according to their restart policy. `service` builds the services jobs are run again on.
+    pub fn open_store(&self, service: impl Fn(WebsocketSystem) -> TaskService) -> io::Result<()> {
+        let path = match &self.settings.store {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let records = JobStore::load(path)?;
+        let mut jobs = self.jobs.lock().unwrap();
+        restore(&mut jobs, records, self.settings.retention);
+        let mut requeued = Vec::new();
+        for (id, job) in jobs.iter_mut() {
+            if job.info.status != JobStatus::Running {
+                continue;
+            }
+            match self
+                .settings
+                .restart_policy(job.info.system, &job.info.task)
+            {
+                RestartPolicy::Requeue => requeued.push(*id),
+                RestartPolicy::Fail => {
+                    tracing::info!("Job {} was interrupted by a restart.", id);
+                    let result = ResultMessage::from_error(
+                        "Server restarted while the job was running.",
+                        Some(job.info.system),
+                    );
+                    job.finish(JobStatus::Failed, Some(result));
+                }
+            }
+        }
+        let store = JobStore::create(path, &store_records(&jobs))?;
+        *self.store.lock().unwrap() = Some(StoreWriter::spawn(store)?);
+        for id in requeued {
+            if let Some(job) = jobs.get_mut(&id) {
+                tracing::info!("Requeued job {}.", id);
+                let request = job.task_request();
+                job.handle = Some(self.spawn(id, service(job.info.system), request));
+            }
+        }
+        tracing::info!("Restored {} jobs from {}.", jobs.len(), path.display());
+        Ok(())
+    }
+
+    pub fn settings(&self) -> &JobSettings {
+        &self.settings
+    }
+
+    /// Runs `request` on `service` in the background.
+    pub fn submit(&self, service: TaskService, request: TaskRequest) -> Result<Uuid, JobError> {
+        self.submit_job(service, request, None)
+    }
+
+    /// Runs `request` on `service` in the background, as a run of `schedule`.
+    pub fn submit_scheduled(
+        &self,
+        schedule: &str,
+        service: TaskService,
+        request: TaskRequest,
+    ) -> Result<Uuid, JobError> {
+        self.submit_job(service, request, Some(schedule.to_string()))
+    }
+
+    fn submit_job(
+        &self,
+        service: TaskService,
+        request: TaskRequest,
+        schedule: Option<String>,
+    ) -> Result<Uuid, JobError> {
+        let id = Uuid::new_v4();
+        let system = request.system;
+        let mut jobs = self.jobs.lock().unwrap();
+        self.purge(&mut jobs);
+        if jobs.len() >= self.settings.max_jobs {
+            return Err(JobError::TooManyJobs(self.settings.max_jobs));
+        }
+        let submitted_at = now_millis();
+        let mut job = Job {
+            info: JobInfo {
+                id: id.to_string(),
+                system,
+                task: request.task.clone(),
+                status: JobStatus::Running,
+                submitted_at,
+                duration: None,
+                schedule,
+            },
+            session: request.session.clone(),
+            request: StoredRequest {
+                id: request.id.clone(),
+                payload: request.payload.clone(),
+                no_cache: request.no_cache,
+                timeout: request
+                    .ctx
+                    .deadline
+                    .saturating_duration_since(tokio::time::Instant::now()),
+            },
+            started: Instant::now(),
+            finished: None,
+            finished_at: 0,
+            result: None,
+            handle: None,
+        };
+        self.record(Some(job.submitted_record()));
+        job.handle = Some(self.spawn(id, service, request));
+        jobs.insert(id, job);
+        tracing::info!("Submitted job {}.", id);
+        Ok(id)
+    }
+
+    fn spawn(&self, id: Uuid, service: TaskService, request: TaskRequest) -> JoinHandle<()> {
+        let system = request.system;
+        let manager = self.clone();
+        // The job only updates its entry once the lock is released
+        tokio_spawn(async move {
+            let result = match service.oneshot(request).await {
+                Ok(TaskResponse::Result(result)) => result,
+                Ok(TaskResponse::Stream(_)) => {
+                    ResultMessage::from_error("Jobs cannot stream results.", Some(system))
+                }
+                Err(e) => ResultMessage::from_error(e, Some(system)),
+            };
+            manager.complete(id, result);
+        })
+    }
+
+    fn complete(&self, id: Uuid, result: ResultMessage) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            if job.info.status == JobStatus::Running {
+                let status = if result.success {
+                    JobStatus::Succeeded
+                } else {
+                    JobStatus::Failed
+                };
+                tracing::info!("Job {} finished: {:?}.", id, status);
+                job.finish(status, Some(result));
+                self.finished(job);
+            }
+        }
+    }
+
+    pub fn status(&self, id: Uuid, identity: Option<&str>) -> Result<JobInfo, JobError> {
+        self.with_job(id, identity, |job| Ok(job.info.clone()))
+    }
+
+    /// Result of a finished job, `None` if it was cancelled.
+    pub fn result(
+        &self,
+        id: Uuid,
+        identity: Option<&str>,
+    ) -> Result<(JobInfo, Option<ResultMessage>), JobError> {
+        self.with_job(id, identity, |job| match job.info.status {
+            JobStatus::Running => Err(JobError::Running(id)),
+            _ => Ok((job.info.clone(), job.result.clone())),
+        })
+    }
+
+    /// Aborts a running job, finished jobs are left as they are. Jobs without an owner can only
+    /// be cancelled by the session that submitted them, and scheduled jobs not at all.
+    pub fn cancel(&self, id: Uuid, session: Option<&SessionInfo>) -> Result<JobInfo, JobError> {
+        let identity = session.and_then(|session| session.identity.as_deref());
+        self.with_job(id, identity, |job| {
+            if job.info.schedule.is_some() {
+                return Err(JobError::Scheduled(id));
+            }
+            if job.session.identity.is_none()
+                && session.map(|session| session.id) != Some(job.session.id)
+            {
+                return Err(JobError::NotSubmitter(id));
+            }
+            if let Some(handle) = job.handle.take() {
+                handle.abort();
+                tracing::info!("Cancelled job {}.", id);
+                job.finish(JobStatus::Cancelled, None);
+                self.finished(job);
+            }
+            Ok(job.info.clone())
+        })
+    }
+
+    /// Finished runs of `schedule` with their results, oldest first.
+    pub fn history(&self, schedule: &str) -> Vec<FinishedJob> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.purge(&mut jobs);
+        let mut history = jobs
+            .values()
+            .filter(|job| job.finished.is_some())
+            .filter(|job| job.info.schedule.as_deref() == Some(schedule))
+            .map(|job| FinishedJob {
+                info: job.info.clone(),
+                result: job.result.clone(),
+            })
+            .collect::<Vec<_>>();
+        history.sort_by_key(|job| job.info.submitted_at);
+        history
+    }
+
+    /// Receives every job that finishes from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<FinishedJob> {
+        self.finished.subscribe()
+    }
+
+    /// Jobs owned by `identity`, oldest first. Jobs without an owner are only accessible to
+    /// the clients that know their ID, so they are never listed.
+    pub fn list(&self, identity: Option<&str>) -> Vec<JobInfo> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.purge(&mut jobs);
+        let mut visible = jobs
+            .values()
+            .filter(|job| identity.is_some() && job.session.identity.as_deref() == identity)
+            .map(|job| job.info.clone())
+            .collect::<Vec<_>>();
+        visible.sort_by_key(|info| info.submitted_at);
+        visible
+    }
+
+    /// Aborts every running job. They are not recorded as cancelled, so their restart policy
+    /// applies when the store is opened again.
+    pub fn stop(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        for job in jobs.values_mut() {
+            if let Some(handle) = job.handle.take() {
+                handle.abort();
+                job.finish(JobStatus::Cancelled, None);
+            }
+        }
+    }
+
+    fn with_job<T>(
+        &self,
+        id: Uuid,
+        identity: Option<&str>,
+        f: impl FnOnce(&mut Job) -> Result<T, JobError>,
+    ) -> Result<T, JobError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.purge(&mut jobs);
+        match jobs.get_mut(&id) {
+            // Jobs of other identities are reported as missing, not to reveal them
+            Some(job) if can_access(job, identity) => f(job),
+            _ => Err(JobError::NotFound(id)),
+        }
+    }
+
+    /// Drops the jobs finished for longer than the retention period, and compacts the store
+    /// once it is mostly made of their records. The store is rewritten on its own thread, from a
+    /// snapshot of the jobs.
+    fn purge(&self, jobs: &mut HashMap<Uuid, Job>) {
+        let retention = self.settings.retention;
+        jobs.retain(|_, job| {
+            job.finished
+                .is_none_or(|finished| finished.elapsed() < retention)
+        });
+        if let Some(store) = self.store.lock().unwrap().as_mut() {
+            if store.records() > 2 * jobs.len() + COMPACT_THRESHOLD {
+                store.compact(store_records(jobs));
+            }
+        }
+    }
+
+    /// Records a finished job and notifies the subscribers.
+    fn finished(&self, job: &Job) {
+        self.record(job.finished_record());
+        // Fails when nobody is subscribed
+        let _ = self.finished.send(FinishedJob {
+            info: job.info.clone(),
+            result: job.result.clone(),
+        });
+    }
+
+    fn record(&self, record: Option<JobRecord>) {
+        if let (Some(store), Some(record)) = (self.store.lock().unwrap().as_mut(), record) {
+            store.append(record);
+        }
+    }
+
+    /// Waits for the jobs recorded so far to be written to the store.
+    pub async fn flush(&self) {
+        let written = self.store.lock().unwrap().as_ref().map(StoreWriter::flush);
+        if let Some(written) = written {
+            // Fails if the writer thread stopped, the records are lost either way
+            let _ = written.await;
+        }
+    }
+}
+
+fn can_access(job: &Job, identity: Option<&str>) -> bool {
+    let owner = job.session.identity.as_deref();
+    owner.is_none() || owner == identity
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Rebuilds the jobs from the records of the store, without the ones past `retention`.
+fn restore(jobs: &mut HashMap<Uuid, Job>, records: Vec<JobRecord>, retention: Duration) {
+    let now = now_millis();
+    for record in records {
+        match record {
+            JobRecord::Submitted {
+                info,
+                session,
+                request,
+            } => {
+                let id = match Uuid::parse_str(&info.id) {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                };
+                jobs.insert(
+                    id,
+                    Job {
+                        info,
+                        session: Arc::new(session),
+                        request,
+                        started: Instant::now(),
+                        finished: None,
+                        finished_at: 0,
+                        result: None,
+                        handle: None,
+                    },
+                );
+            }
+            JobRecord::Finished {
+                id,
+                status,
+                duration,
+                finished_at,
+                result,
+            } => {
+                let id = match Uuid::parse_str(&id) {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                };
+                let elapsed = Duration::from_millis(now.saturating_sub(finished_at));
+                if elapsed >= retention {
+                    jobs.remove(&id);
+                } else if let Some(job) = jobs.get_mut(&id) {
+                    job.info.status = status;
+                    job.info.duration = duration;
+                    job.finished = Some(
+                        Instant::now()
+                            .checked_sub(elapsed)
+                            .unwrap_or_else(Instant::now),
+                    );
+                    job.finished_at = finished_at;
+                    job.result = result;
+                }
+            }
+        }
+    }
+}
+
+/// Records describing `jobs`, oldest first.
+fn store_records(jobs: &HashMap<Uuid, Job>) -> Vec<JobRecord> {
+    let mut sorted = jobs.values().collect::<Vec<_>>();
+    sorted.sort_by_key(|job| job.info.submitted_at);
+    sorted
+        .into_iter()
+        .flat_map(|job| std::iter::once(job.submitted_record()).chain(job.finished_record()))
+        .collect()
+}
@@ -0,0 +1,184 @@
+//! Runs the configured schedules as background jobs, whether or not a session is open.
+use super::{FinishedJob, JobInfo, JobStatus};
+use crate::{
+    configuration::ScheduleSettings,
+    message::Protocol,
+    service::TaskRequest,
+    shutdown::Shutdown,
+    subsystems::{SessionInfo, SessionStates, SubsystemRegistry, TaskContext},
+    telemetry::tokio_spawn,
+};
+use chrono::Utc;
+use serde::Serialize;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time::{sleep_until, Instant};
+use uuid::Uuid;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScheduleError {
+    #[error("Schedule {0} needs exactly one of `every` and `cron`.")]
+    Trigger(String),
+    #[error("Schedule {0} runs every 0ms.")]
+    ZeroInterval(String),
+    #[error("Invalid cron expression for schedule {name}: {source}")]
+    Cron {
+        name: String,
+        source: cron::error::Error,
+    },
+    #[error("Schedule {0} is defined twice.")]
+    Duplicate(String),
+}
+
+enum Trigger {
+    Every(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Trigger {
+    /// Next time the schedule fires after `now`, `None` once the cron expression is exhausted.
+    fn next(&self, now: Instant) -> Option<Instant> {
+        match self {
+            Trigger::Every(every) => Some(now + *every),
+            Trigger::Cron(schedule) => {
+                let next = schedule.upcoming(Utc).next()?;
+                let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                Some(now + wait)
+            }
+        }
+    }
+}
+
+struct Schedule {
+    settings: ScheduleSettings,
+    trigger: Trigger,
+}
+
+/// Submits the task of every schedule when it fires. A run is skipped while the previous run
+/// of the same schedule is still going.
+pub struct Scheduler {
+    schedules: Vec<Schedule>,
+}
+
+impl Scheduler {
+    pub fn new(settings: &[ScheduleSettings]) -> Result<Self, ScheduleError> {
+        let mut schedules: Vec<Schedule> = Vec::with_capacity(settings.len());
+        for settings in settings {
+            let name = settings.name.clone();
+            if schedules.iter().any(|s| s.settings.name == name) {
+                return Err(ScheduleError::Duplicate(name));
+            }
+            let trigger = match (settings.every, &settings.cron) {
+                (Some(every), None) if every.is_zero() => {
+                    return Err(ScheduleError::ZeroInterval(name))
+                }
+                (Some(every), None) => Trigger::Every(every),
+                (None, Some(cron)) => cron::Schedule::from_str(cron)
+                    .map(|schedule| Trigger::Cron(Box::new(schedule)))
+                    .map_err(|source| ScheduleError::Cron { name, source })?,
+                _ => return Err(ScheduleError::Trigger(name)),
+            };
+            schedules.push(Schedule {
+                settings: settings.clone(),
+                trigger,
+            });
+        }
+        Ok(Self { schedules })
+    }
+
+    /// Runs every schedule until the server shuts down.
+    pub fn start(self, registry: SubsystemRegistry, shutdown: Shutdown) {
+        // Scheduled jobs are not owned by any identity, sessions find them with `history`
+        let session = Arc::new(SessionInfo {
+            id: Uuid::new_v4(),
+            peer_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            identity: None,
+            protocol: Protocol::Native,
+        });
+        for schedule in self.schedules {
+            tokio_spawn(run_schedule(
+                schedule,
+                registry.clone(),
+                session.clone(),
+                shutdown.clone(),
+            ));
+        }
+    }
+}
+
+async fn run_schedule(
+    schedule: Schedule,
+    registry: SubsystemRegistry,
+    session: Arc<SessionInfo>,
+    shutdown: Shutdown,
+) {
+    let settings = schedule.settings;
+    let manager = registry.jobs.manager().clone();
+    let mut last = None;
+    let mut next = schedule.trigger.next(Instant::now());
+    while let Some(at) = next {
+        tokio::select! {
+            _ = sleep_until(at) => {}
+            _ = shutdown.triggered() => return,
+        }
+        next = schedule.trigger.next(at.max(Instant::now()));
+        let running = last
+            .and_then(|id| manager.status(id, None).ok())
+            .is_some_and(|info| info.status == JobStatus::Running);
+        if running {
+            tracing::warn!(
+                "Skipping schedule {}, the last run is still going.",
+                settings.name
+            );
+            continue;
+        }
+        let system = settings.system;
+        let request = TaskRequest {
+            system,
+            task: settings.task.clone(),
+            payload: settings.payload.clone(),
+            id: None,
+            stream: false,
+            no_cache: true,
+            idempotency_key: None,
+            ctx: TaskContext::detached(system, manager.settings().timeout),
+            session: session.clone(),
+        };
+        let service = registry.service(system, &SessionStates::default());
+        match manager.submit_scheduled(&settings.name, service, request) {
+            Ok(id) => last = Some(id),
+            Err(e) => tracing::error!("Failed to run schedule {}: {}", settings.name, e),
+        }
+    }
+    tracing::info!("Schedule {} has no more runs.", settings.name);
+}
+
+/// `{"type": "schedule_result"}` message, pushed to the sessions subscribed to a schedule when
+/// one of its runs finishes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "schedule_result")]
+pub struct ScheduleResult {
+    pub schedule: String,
+    pub job: JobInfo,
+    /// `None` if the run was cancelled
+    pub success: Option<bool>,
+    pub payload: serde_json::Value,
+}
+
+impl ScheduleResult {
+    /// `None` for jobs not submitted by a schedule.
+    pub fn new(job: FinishedJob) -> Option<Self> {
+        Some(Self {
+            schedule: job.info.schedule.clone()?,
+            success: job.result.as_ref().map(|result| result.success),
+            payload: job
+                .result
+                .map_or(serde_json::Value::Null, |result| result.payload),
+            job: job.info,
+        })
+    }
+}
@@ -0,0 +1,192 @@
+//! Append-only JSONL file the background jobs are recorded in.
+//!
+//! Every job adds a `submitted` record when it starts and a `finished` record when it ends.
+//! The file is rewritten with only the retained jobs on startup, and whenever most of its
+//! records belong to purged jobs. Once opened, the file is only written by the thread of its
+//! [`StoreWriter`], so the runtime never waits on the disk.
+use super::{JobInfo, JobStatus};
+use crate::{
+    message::{RequestId, ResultMessage},
+    subsystems::SessionInfo,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+/// What is needed to run a job again.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    pub payload: serde_json::Value,
+    pub no_cache: bool,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobRecord {
+    Submitted {
+        info: JobInfo,
+        session: SessionInfo,
+        request: StoredRequest,
+    },
+    Finished {
+        id: String,
+        status: JobStatus,
+        duration: Option<u64>,
+        /// Milliseconds since the Unix epoch
+        finished_at: u64,
+        result: Option<ResultMessage>,
+    },
+}
+
+pub struct JobStore {
+    path: PathBuf,
+    file: File,
+    /// Records in the file
+    records: usize,
+}
+
+impl JobStore {
+    /// Reads the records of `path`, skipping the lines that cannot be parsed.
+    pub fn load(path: &Path) -> io::Result<Vec<JobRecord>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut records = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    tracing::warn!("Skipping line {} of {}: {}", number + 1, path.display(), e)
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Replaces the file at `path` with `records` and opens it for appending.
+    pub fn create(path: &Path, records: &[JobRecord]) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for record in records {
+                writeln!(file, "{}", serde_json::to_string(record)?)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            records: records.len(),
+        })
+    }
+
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    pub fn append(&mut self, record: &JobRecord) {
+        let written = serde_json::to_string(record)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(self.file, "{}", line));
+        match written {
+            Ok(()) => self.records += 1,
+            Err(e) => tracing::error!("Failed to record job in {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Rewrites the file with only `records`.
+    pub fn compact(&mut self, records: &[JobRecord]) {
+        match Self::create(&self.path, records) {
+            Ok(store) => *self = store,
+            Err(e) => tracing::error!("Failed to compact {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+enum Command {
+    Append(JobRecord),
+    Compact(Vec<JobRecord>),
+    /// Answered once the commands sent before are written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Sends the records to a thread owning the [`JobStore`], which writes them in order. The thread
+/// stops once the writer is dropped.
+pub struct StoreWriter {
+    commands: mpsc::Sender<Command>,
+    /// Records in the file once the commands sent are written
+    records: usize,
+}
+
+impl StoreWriter {
+    pub fn spawn(mut store: JobStore) -> io::Result<Self> {
+        let records = store.records();
+        let (commands, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("job-store".to_string())
+            .spawn(move || {
+                for command in receiver {
+                    match command {
+                        Command::Append(record) => store.append(&record),
+                        Command::Compact(records) => store.compact(&records),
+                        Command::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })?;
+        Ok(Self { commands, records })
+    }
+
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    pub fn append(&mut self, record: JobRecord) {
+        self.send(Command::Append(record));
+        self.records += 1;
+    }
+
+    /// Rewrites the file with only `records`.
+    pub fn compact(&mut self, records: Vec<JobRecord>) {
+        self.records = records.len();
+        self.send(Command::Compact(records));
+    }
+
+    /// Waits for the records sent so far to be written.
+    pub fn flush(&self) -> oneshot::Receiver<()> {
+        let (done, written) = oneshot::channel();
+        self.send(Command::Flush(done));
+        written
+    }
+
+    fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            tracing::error!("Job store writer stopped, records are lost.");
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! JSON-RPC 2.0 compatibility mode.
+//!
+//! Requests use `"<system>.<task>"` as method and `params` as the task payload, e.g.
+//! `{"jsonrpc": "2.0", "method": "python_repo.get_files", "params": "src", "id": 1}`.
+use crate::{
+    message::{ClientMessage, ErrorCode, ErrorPayload, RequestId, ResultMessage},
+    subsystems::WebsocketSystem,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequest, RequestParts},
+    http::header::SEC_WEBSOCKET_PROTOCOL,
+};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+
+/// Websocket subprotocol that selects the JSON-RPC mode.
+pub const SUBPROTOCOL: &str = "jsonrpc-2.0";
+
+/// Whether the client offered the JSON-RPC [`SUBPROTOCOL`] on the upgrade request.
+pub struct SubprotocolRequested(pub bool);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for SubprotocolRequested {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let requested = req.headers().is_some_and(|headers| {
+            headers
+                .get_all(SEC_WEBSOCKET_PROTOCOL)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|protocol| protocol.trim() == SUBPROTOCOL)
+        });
+        Ok(Self(requested))
+    }
+}
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// Task failed while running.
+pub const TASK_FAILED: i64 = -32000;
+pub const TIMEOUT: i64 = -32001;
+pub const RESULT_TOO_LARGE: i64 = -32002;
+pub const UNAUTHORIZED: i64 = -32003;
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+    /// `Some(None)` for `"id": null`, which is a request, unlike a missing id
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    id: Option<Option<RequestId>>,
+}
+
+/// A request that can be dispatched to a subsystem.
+pub struct Call {
+    pub message: ClientMessage,
+    /// Notifications do not receive a response
+    pub notification: bool,
+}
+
+/// Frame from a JSON-RPC client, invalid requests are already mapped to their error result.
+/// Notifications that cannot be dispatched are `None`, as they are never answered.
+pub enum Frame {
+    Single(Result<Option<Call>, Box<ResultMessage>>),
+    Batch(Vec<Result<Option<Call>, Box<ResultMessage>>>),
+}
+
+impl Frame {
+    pub fn parse(text: &str) -> Self {
+        match serde_json::from_str::<serde_json::Value>(text) {
+            Err(e) => Self::Single(Err(Box::new(ResultMessage::from_error_code(
+                ErrorCode::ParseError,
+                e,
+                None,
+            )))),
+            Ok(serde_json::Value::Array(values)) if values.is_empty() => {
+                Self::Single(Err(Box::new(ResultMessage::from_error_code(
+                    ErrorCode::InvalidRequest,
+                    "Empty batch.",
+                    None,
+                ))))
+            }
+            Ok(serde_json::Value::Array(values)) => {
+                Self::Batch(values.into_iter().map(parse_request).collect())
+            }
+            Ok(value) => Self::Single(parse_request(value)),
+        }
+    }
+}
+
+fn parse_request(value: serde_json::Value) -> Result<Option<Call>, Box<ResultMessage>> {
+    let notification = value.get("id").is_none();
+    // Keep the id of malformed requests when possible
+    let id = value
+        .get("id")
+        .and_then(|id| serde_json::from_value::<RequestId>(id.clone()).ok());
+    match to_call(value) {
+        Ok(call) => Ok(Some(call)),
+        // Invalid requests are still answered, the spec requires it even without an id
+        Err((ErrorCode::UnknownMethod, _)) if notification => Ok(None),
+        Err((code, e)) => Err(Box::new(
+            ResultMessage::from_error_code(code, e, None).with_id(id),
+        )),
+    }
+}
+
+fn to_call(value: serde_json::Value) -> Result<Call, (ErrorCode, String)> {
+    let request = serde_json::from_value::<Request>(value)
+        .map_err(|e| (ErrorCode::InvalidRequest, e.to_string()))?;
+    if request.jsonrpc != "2.0" {
+        return Err((
+            ErrorCode::InvalidRequest,
+            "Unsupported JSON-RPC version.".into(),
+        ));
+    }
+    let (system, task) = request
+        .method
+        .split_once('.')
+        .and_then(|(system, task)| {
+            let system = serde_json::from_value::<WebsocketSystem>(system.into()).ok()?;
+            Some((system, task.to_string()))
+        })
+        .ok_or_else(|| {
+            (
+                ErrorCode::UnknownMethod,
+                format!("Method not found: {:?}", request.method),
+            )
+        })?;
+    let notification = request.id.is_none();
+    Ok(Call {
+        message: ClientMessage {
+            id: request.id.flatten(),
+            system,
+            task,
+            payload: request.params,
+            stream: false,
+            progress: false,
+            timeout: None,
+            no_cache: false,
+            idempotency_key: None,
+            background: false,
+        },
+        notification,
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ResponseError>,
+    pub id: Option<RequestId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl From<ResultMessage> for Response {
+    fn from(msg: ResultMessage) -> Self {
+        let (result, error) = if msg.success {
+            (Some(msg.payload), None)
+        } else {
+            (None, Some(ResponseError::from(msg.payload)))
+        };
+        Self {
+            jsonrpc: "2.0".into(),
+            result,
+            error,
+            id: msg.id,
+        }
+    }
+}
+
+impl From<serde_json::Value> for ResponseError {
+    fn from(payload: serde_json::Value) -> Self {
+        match serde_json::from_value::<ErrorPayload>(payload.clone()) {
+            Ok(error) => {
+                let code = match error.code {
+                    ErrorCode::ParseError => PARSE_ERROR,
+                    ErrorCode::InvalidRequest
+                    | ErrorCode::MessageTooLarge
+                    | ErrorCode::BatchTooLarge
+                    | ErrorCode::HandshakeFailed => INVALID_REQUEST,
+                    ErrorCode::UnknownMethod | ErrorCode::UnknownTask => METHOD_NOT_FOUND,
+                    ErrorCode::Timeout => TIMEOUT,
+                    ErrorCode::ResultTooLarge => RESULT_TOO_LARGE,
+                    ErrorCode::Unauthorized => UNAUTHORIZED,
+                };
+                Self {
+                    code,
+                    message: error.message,
+                    data: None,
+                }
+            }
+            Err(_) => Self {
+                code: TASK_FAILED,
+                message: "Task failed.".into(),
+                data: Some(payload),
+            },
+        }
+    }
+}
+
+/// Serializes a server notification.
+pub fn encode_notification(
+    method: &str,
+    params: serde_json::Value,
+) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+    }))
+}
+
+/// Serializes a result as a JSON-RPC response.
+pub fn encode(msg: ResultMessage) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&Response::from(msg))
+}
@@ -1,6 +1,20 @@
 pub mod configuration;
 pub mod error;
+pub mod graphql;
+pub mod handshake;
+pub mod heartbeat;
+pub mod identity;
+pub mod idle;
+pub mod jobs;
+pub mod jsonrpc;
+pub mod limits;
 pub mod message;
+pub mod pipeline;
+pub mod progress;
+pub mod routes;
+pub mod service;
+pub mod session;
+pub mod shutdown;
 pub mod startup;
 pub mod subsystems;
 pub mod telemetry;
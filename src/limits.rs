@@ -0,0 +1,186 @@
+use crate::{configuration::ConnectionLimitSettings, identity::Identity};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, Extension, FromRequest, RequestParts},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, thiserror::Error, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimitError {
+    #[error("Too many concurrent sessions.")]
+    TooManySessions,
+    #[error("Too many concurrent sessions from this IP.")]
+    TooManySessionsForIp,
+    #[error("Too many concurrent sessions for this identity.")]
+    TooManySessionsForIdentity,
+}
+
+impl IntoResponse for ConnectionLimitError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::TooManySessions => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManySessionsForIp | Self::TooManySessionsForIdentity => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_identity: HashMap<String, usize>,
+    rejected_total: usize,
+    rejected_per_ip: usize,
+    rejected_per_identity: usize,
+}
+
+/// Snapshot of the current connection usage.
+#[derive(Debug, Serialize)]
+pub struct ConnectionUsage {
+    pub sessions: usize,
+    pub max_sessions: usize,
+    pub peer_ips: usize,
+    pub max_sessions_per_ip: usize,
+    pub identities: usize,
+    pub max_sessions_per_identity: usize,
+    pub rejected_total: usize,
+    pub rejected_per_ip: usize,
+    pub rejected_per_identity: usize,
+}
+
+/// Keeps track of the active sessions, globally, per peer IP and per identity.
+pub struct ConnectionLimiter {
+    settings: ConnectionLimitSettings,
+    counts: Mutex<ConnectionCounts>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(settings: ConnectionLimitSettings) -> Self {
+        Self {
+            settings,
+            counts: Mutex::new(ConnectionCounts::default()),
+        }
+    }
+
+    /// Reserves a session slot, the slot is released when the returned guard is dropped.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        identity: Option<String>,
+    ) -> Result<ConnectionGuard, ConnectionLimitError> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.total >= self.settings.max_sessions {
+            counts.rejected_total += 1;
+            return Err(ConnectionLimitError::TooManySessions);
+        }
+        if counts.per_ip.get(&ip).copied().unwrap_or(0) >= self.settings.max_sessions_per_ip {
+            counts.rejected_per_ip += 1;
+            return Err(ConnectionLimitError::TooManySessionsForIp);
+        }
+        if let Some(identity) = &identity {
+            if counts.per_identity.get(identity).copied().unwrap_or(0)
+                >= self.settings.max_sessions_per_identity
+            {
+                counts.rejected_per_identity += 1;
+                return Err(ConnectionLimitError::TooManySessionsForIdentity);
+            }
+            *counts.per_identity.entry(identity.clone()).or_default() += 1;
+        }
+        *counts.per_ip.entry(ip).or_default() += 1;
+        counts.total += 1;
+        Ok(ConnectionGuard {
+            limiter: self.clone(),
+            ip,
+            identity,
+        })
+    }
+
+    pub fn usage(&self) -> ConnectionUsage {
+        let counts = self.counts.lock().unwrap();
+        ConnectionUsage {
+            sessions: counts.total,
+            max_sessions: self.settings.max_sessions,
+            peer_ips: counts.per_ip.len(),
+            max_sessions_per_ip: self.settings.max_sessions_per_ip,
+            identities: counts.per_identity.len(),
+            max_sessions_per_identity: self.settings.max_sessions_per_identity,
+            rejected_total: counts.rejected_total,
+            rejected_per_ip: counts.rejected_per_ip,
+            rejected_per_identity: counts.rejected_per_identity,
+        }
+    }
+
+    fn release(&self, ip: &IpAddr, identity: Option<&String>) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        decrement(&mut counts.per_ip, ip);
+        if let Some(identity) = identity {
+            decrement(&mut counts.per_identity, identity);
+        }
+    }
+}
+
+fn decrement<K: std::hash::Hash + Eq>(map: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = map.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+/// A session slot reserved on the [`ConnectionLimiter`].
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+    identity: Option<String>,
+}
+
+impl ConnectionGuard {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+}
+
+/// Reserves a session slot for the peer, rejecting the request when a limit is reached.
+#[async_trait]
+impl<B: Send> FromRequest<B> for ConnectionGuard {
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Identity(identity) = match Identity::from_request(req).await {
+            Ok(identity) => identity,
+            Err(e) => match e {},
+        };
+        let Extension(limiter) = Extension::<Arc<ConnectionLimiter>>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        limiter.try_acquire(addr.ip(), identity).map_err(|e| {
+            tracing::info!("Rejected websocket upgrade from {}: {}", addr, e);
+            e.into_response()
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.release(&self.ip, self.identity.as_ref());
+    }
+}
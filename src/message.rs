@@ -1,61 +1,379 @@
-use crate::subsystems::WebsocketSystem;
+use crate::{
+    handshake::{ClientHello, HandshakeMessage},
+    heartbeat::{ClientPing, PongMessage},
+    idle::{IdleWarning, InFlightGuard},
+    jobs::scheduler::ScheduleResult,
+    pipeline::PipelineRequest,
+    progress::Progress,
+    subsystems::WebsocketSystem,
+};
+use axum::extract::ws::CloseFrame;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
+use std::time::Duration;
+use tokio::sync::oneshot;
 
 /// Internal messages.
 #[derive(Debug)]
 pub enum WebsocketMessage {
     TaskResult(ResultMessage),
+    BatchResult(BatchResultMessage),
+    Handshake(HandshakeMessage),
+    Pong(PongMessage),
+    IdleWarning(IdleWarning),
+    ScheduleResult(ScheduleResult),
     Ping(Vec<u8>),
-    Close,
+    Close(Option<CloseFrame<'static>>),
+}
+
+/// Wire format of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Native,
+    /// See [`crate::jsonrpc`]
+    JsonRpc,
+    /// See [`crate::graphql`], its sessions are not driven by [`crate::websocket`]
+    #[serde(rename = "graphql")]
+    GraphQL,
+}
+
+/// Identifier chosen by the client to match results with requests.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(i64),
+    String(String),
 }
 
 /// Message from client.
+#[serde_as]
 #[derive(Deserialize)]
 pub struct ClientMessage {
+    #[serde(default)]
+    pub id: Option<RequestId>,
     pub system: WebsocketSystem,
     pub task: String,
     #[serde(default = "serde_json::Value::default")]
     pub payload: serde_json::Value,
+    /// Receive the result as a sequence of chunk messages
+    #[serde(default)]
+    pub stream: bool,
+    /// Receive progress messages while the task runs
+    #[serde(default)]
+    pub progress: bool,
+    /// Time limit for the task, in milliseconds. Capped by the server timeout
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub timeout: Option<Duration>,
+    /// Run the task even if a cached result exists
+    #[serde(default)]
+    pub no_cache: bool,
+    /// Run the task as a background job, the reply holds the job ID
+    #[serde(default)]
+    pub background: bool,
+    /// A retried message with the same key gets the result of the first one instead of running
+    /// the task again
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+/// Frame from client, either a single message, a batch of them, a pipeline, a hello or a ping.
+pub enum ClientFrame {
+    Hello(ClientHello),
+    Ping(ClientPing),
+    Message(ClientMessage),
+    /// Results are sent individually as they complete
+    Batch(Vec<Result<ClientMessage, serde_json::Error>>),
+    /// Results are sent together in a single [`BatchResultMessage`]
+    CombinedBatch(Vec<Result<ClientMessage, serde_json::Error>>),
+    Pipeline(PipelineRequest),
+}
+
+/// Batch of messages whose results can be combined in a single frame.
+#[derive(Deserialize)]
+struct BatchRequest {
+    batch: Vec<serde_json::Value>,
+    #[serde(default)]
+    combine: bool,
+}
+
+impl ClientFrame {
+    /// Parses a text frame, which may be a message, an array of messages, an object
+    /// `{"batch": [..], "combine": true}`, a `{"pipeline": [..]}`, a `{"type": "hello", ..}`
+    /// handshake or a `{"type": "ping"}` heartbeat.
+    ///
+    /// Batch elements are parsed independently so an invalid one only fails its own result.
+    pub fn parse(text: &str) -> Result<Self, serde_json::Error> {
+        let parse_all = |values: Vec<serde_json::Value>| {
+            values
+                .into_iter()
+                .map(serde_json::from_value::<ClientMessage>)
+                .collect()
+        };
+        match serde_json::from_str::<serde_json::Value>(text)? {
+            serde_json::Value::Array(values) => Ok(Self::Batch(parse_all(values))),
+            value if value.get("type").is_some() => match value["type"].as_str() {
+                Some("hello") => serde_json::from_value(value).map(Self::Hello),
+                Some("ping") => serde_json::from_value(value).map(Self::Ping),
+                _ => Err(serde::de::Error::custom(format!(
+                    "unknown message type: {}",
+                    value["type"]
+                ))),
+            },
+            value if value.get("batch").is_some() => {
+                let request = serde_json::from_value::<BatchRequest>(value)?;
+                let messages = parse_all(request.batch);
+                Ok(if request.combine {
+                    Self::CombinedBatch(messages)
+                } else {
+                    Self::Batch(messages)
+                })
+            }
+            value if value.get("pipeline").is_some() => {
+                serde_json::from_value(value).map(Self::Pipeline)
+            }
+            value => serde_json::from_value(value).map(Self::Message),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct TaskMessage {
+    pub id: Option<RequestId>,
     pub name: String,
     pub payload: serde_json::Value,
+    pub stream: bool,
+    pub progress: bool,
+    pub timeout: Option<Duration>,
+    pub no_cache: bool,
+    pub idempotency_key: Option<String>,
+    /// Where to send the final result, instead of the session
+    pub reply: Option<oneshot::Sender<ResultMessage>>,
+    /// Keeps the session from being idle while the task runs
+    pub in_flight: Option<InFlightGuard>,
 }
 
 impl From<ClientMessage> for TaskMessage {
     fn from(msg: ClientMessage) -> Self {
         Self {
+            id: msg.id,
             name: msg.task,
             payload: msg.payload,
+            stream: msg.stream,
+            progress: msg.progress,
+            timeout: msg.timeout,
+            no_cache: msg.no_cache,
+            idempotency_key: msg.idempotency_key,
+            reply: None,
+            in_flight: None,
         }
     }
 }
 
 /// Messages to send to client.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResultMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
     pub system: Option<WebsocketSystem>,
     pub success: bool,
     pub payload: serde_json::Value,
+    /// The payload was cut to fit the maximum outbound message size
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Position of this message in a streamed result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<ChunkInfo>,
+    /// Intermediate progress of a running task, the final result follows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<Progress>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub index: usize,
+    /// This is the final chunk of the result
+    pub last: bool,
+}
+
+/// Results of a combined batch, in the same order as the requests.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchResultMessage {
+    pub batch: Vec<ResultMessage>,
+}
+
+impl BatchResultMessage {
+    /// Serializes the batch, if it exceeds `max_size` bytes every result gets an equal share
+    /// of the size and is truncated as in [`ResultMessage::to_json_limited`].
+    pub fn to_json_limited(self, max_size: usize) -> Result<String, serde_json::Error> {
+        let json = serde_json::to_string(&self)?;
+        if json.len() <= max_size {
+            return Ok(json);
+        }
+        let budget = max_size / self.batch.len().max(1);
+        let results = self
+            .batch
+            .into_iter()
+            .map(|result| result.to_json_limited(budget))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(format!("{{\"batch\":[{}]}}", results.join(",")))
+    }
+}
+
+/// Machine readable error codes for structured errors.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MessageTooLarge,
+    ResultTooLarge,
+    Timeout,
+    BatchTooLarge,
+    ParseError,
+    InvalidRequest,
+    UnknownMethod,
+    UnknownTask,
+    HandshakeFailed,
+    Unauthorized,
+}
+
+/// Payload of structured error results.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    pub message: String,
 }
 
 impl ResultMessage {
     pub fn from_json(payload: serde_json::Value, system: Option<WebsocketSystem>) -> Self {
         Self {
+            id: None,
             system,
             success: true,
             payload,
+            truncated: false,
+            chunk: None,
+            progress: None,
         }
     }
 
     pub fn from_error<E: ToString>(e: E, system: Option<WebsocketSystem>) -> Self {
         let payload = serde_json::Value::String(e.to_string());
         Self {
+            id: None,
+            system,
+            success: false,
+            payload,
+            truncated: false,
+            chunk: None,
+            progress: None,
+        }
+    }
+
+    pub fn from_progress(progress: Progress, system: Option<WebsocketSystem>) -> Self {
+        Self {
+            progress: Some(progress),
+            ..Self::from_json(serde_json::Value::Null, system)
+        }
+    }
+
+    pub fn with_id(mut self, id: Option<RequestId>) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn with_chunk(mut self, index: usize, last: bool) -> Self {
+        self.chunk = Some(ChunkInfo { index, last });
+        self
+    }
+
+    pub fn from_error_code<E: ToString>(
+        code: ErrorCode,
+        e: E,
+        system: Option<WebsocketSystem>,
+    ) -> Self {
+        let payload = serde_json::json!(ErrorPayload {
+            code,
+            message: e.to_string(),
+        });
+        Self {
+            id: None,
             system,
             success: false,
             payload,
+            truncated: false,
+            chunk: None,
+            progress: None,
+        }
+    }
+
+    /// Serializes the message, truncating the payload when the result exceeds `max_size` bytes.
+    ///
+    /// Arrays keep their longest prefix that fits and strings are cut, other payloads are
+    /// replaced by a `result_too_large` error.
+    pub fn to_json_limited(mut self, max_size: usize) -> Result<String, serde_json::Error> {
+        let json = serde_json::to_string(&self)?;
+        if json.len() <= max_size {
+            return Ok(json);
+        }
+
+        self.truncated = true;
+        let best = match std::mem::take(&mut self.payload) {
+            serde_json::Value::Array(items) => self.longest_fit(items.len(), max_size, |n| {
+                serde_json::Value::Array(items[..n].to_vec())
+            })?,
+            serde_json::Value::String(text) => {
+                let boundaries = text
+                    .char_indices()
+                    .map(|(i, _)| i)
+                    .chain(std::iter::once(text.len()))
+                    .collect::<Vec<_>>();
+                self.longest_fit(boundaries.len() - 1, max_size, |n| {
+                    serde_json::Value::String(text[..boundaries[n]].to_string())
+                })?
+            }
+            _ => None,
+        };
+
+        match best {
+            Some(json) => Ok(json),
+            None => {
+                let mut error = Self::from_error_code(
+                    ErrorCode::ResultTooLarge,
+                    format!(
+                        "Result exceeds the maximum message size of {} bytes.",
+                        max_size
+                    ),
+                    self.system,
+                )
+                .with_id(self.id);
+                error.chunk = self.chunk;
+                serde_json::to_string(&error)
+            }
+        }
+    }
+
+    /// Binary search for the largest `n <= len` such that the message with `payload(n)` fits
+    /// in `max_size`, returning its serialization.
+    fn longest_fit(
+        &mut self,
+        len: usize,
+        max_size: usize,
+        payload: impl Fn(usize) -> serde_json::Value,
+    ) -> Result<Option<String>, serde_json::Error> {
+        let (mut lo, mut hi) = (0, len + 1);
+        let mut best = None;
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            self.payload = payload(mid);
+            let json = serde_json::to_string(self)?;
+            if json.len() <= max_size {
+                best = Some(json);
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
         }
+        Ok(best)
     }
 }
@@ -0,0 +1,184 @@
+//! Pipelines, sequences of tasks run on the server where later steps use earlier results.
+//!
+//! A step payload may contain `{"$ref": "/<step>/<pointer>"}` objects, which are replaced by
+//! the value at the JSON pointer in the result of an earlier step. Steps are referenced by name
+//! or by index, e.g. `{"$ref": "/files/0"}` or `{"$ref": "/0/0"}`.
+use crate::{
+    configuration::WebsocketSettings,
+    message::{RequestId, ResultMessage},
+    progress::ProgressReporter,
+    service::{TaskRequest, TaskResponse, TaskService},
+    session::SessionContext,
+    subsystems::{TaskContext, WebsocketSystem},
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tower::ServiceExt;
+
+/// Key of the objects replaced by an earlier result.
+const REFERENCE: &str = "$ref";
+
+/// `{"pipeline": [..]}` request.
+#[derive(Debug, Deserialize)]
+pub struct PipelineRequest {
+    #[serde(default)]
+    pub id: Option<RequestId>,
+    pub pipeline: Vec<PipelineStep>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PipelineStep {
+    /// Lets later steps reference the result by name instead of index
+    #[serde(default)]
+    pub name: Option<String>,
+    pub system: WebsocketSystem,
+    pub task: String,
+    #[serde(default)]
+    pub payload: serde_json::Value,
+    #[serde(default)]
+    pub no_cache: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PipelineError {
+    #[error("Reference {0:?} must be a string starting with a step.")]
+    InvalidReference(serde_json::Value),
+    #[error("Reference {0} is not to an earlier step.")]
+    UnknownStep(String),
+    #[error("Reference {0} points to nothing.")]
+    NotFound(String),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Succeeded,
+    Failed,
+    /// Not run, as an earlier step failed
+    Skipped,
+}
+
+/// Outcome of a step, in the combined result of the pipeline.
+#[derive(Debug, Serialize)]
+pub struct StepResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub system: WebsocketSystem,
+    pub task: String,
+    pub status: StepStatus,
+    pub payload: serde_json::Value,
+}
+
+/// Runs the steps in order, stopping at the first failure. The result holds the outcome of
+/// every step as `{"steps": [..]}` and only succeeds if every step did.
+pub async fn run_pipeline(
+    request: PipelineRequest,
+    service: impl Fn(WebsocketSystem) -> TaskService,
+    settings: &WebsocketSettings,
+    session: SessionContext,
+) -> ResultMessage {
+    let mut steps: Vec<StepResult> = Vec::with_capacity(request.pipeline.len());
+    let mut failed = false;
+    for step in request.pipeline {
+        let system = step.system;
+        if failed {
+            steps.push(StepResult {
+                name: step.name,
+                system,
+                task: step.task,
+                status: StepStatus::Skipped,
+                payload: serde_json::Value::Null,
+            });
+            continue;
+        }
+        let result = match resolve(step.payload, &steps) {
+            Ok(payload) => {
+                let request = TaskRequest {
+                    system,
+                    task: step.task.clone(),
+                    payload,
+                    id: None,
+                    stream: false,
+                    no_cache: step.no_cache,
+                    idempotency_key: None,
+                    ctx: TaskContext {
+                        progress: ProgressReporter::disabled(system),
+                        deadline: Instant::now() + settings.task_timeout(system),
+                        session: Some(session.clone()),
+                    },
+                    session: session.info.clone(),
+                };
+                match service(system).oneshot(request).await {
+                    Ok(TaskResponse::Result(result)) => result,
+                    Ok(TaskResponse::Stream(_)) => {
+                        ResultMessage::from_error("Pipeline steps cannot stream.", Some(system))
+                    }
+                    Err(e) => ResultMessage::from_error(e, Some(system)),
+                }
+            }
+            Err(e) => ResultMessage::from_error(e, Some(system)),
+        };
+        failed = !result.success;
+        steps.push(StepResult {
+            name: step.name,
+            system,
+            task: step.task,
+            status: if result.success {
+                StepStatus::Succeeded
+            } else {
+                StepStatus::Failed
+            },
+            payload: result.payload,
+        });
+    }
+    let success = steps
+        .iter()
+        .all(|step| step.status == StepStatus::Succeeded);
+    let mut result = ResultMessage::from_json(serde_json::json!({ "steps": steps }), None);
+    result.success = success;
+    result.with_id(request.id)
+}
+
+/// Replaces the references in `payload` by the results of the earlier `steps`.
+fn resolve(
+    payload: serde_json::Value,
+    steps: &[StepResult],
+) -> Result<serde_json::Value, PipelineError> {
+    use serde_json::Value;
+    match payload {
+        Value::Object(object) if object.len() == 1 && object.contains_key(REFERENCE) => {
+            let reference = &object[REFERENCE];
+            let pointer = reference
+                .as_str()
+                .filter(|pointer| pointer.starts_with('/'))
+                .ok_or_else(|| PipelineError::InvalidReference(reference.clone()))?;
+            let path = &pointer[1..];
+            let (step, rest) = match path.find('/') {
+                Some(i) => path.split_at(i),
+                None => (path, ""),
+            };
+            let result = steps
+                .iter()
+                .enumerate()
+                .find(|(i, result)| result.name.as_deref() == Some(step) || i.to_string() == step)
+                .map(|(_, result)| result)
+                .ok_or_else(|| PipelineError::UnknownStep(pointer.to_string()))?;
+            result
+                .payload
+                .pointer(rest)
+                .cloned()
+                .ok_or_else(|| PipelineError::NotFound(pointer.to_string()))
+        }
+        Value::Object(object) => object
+            .into_iter()
+            .map(|(key, value)| Ok((key, resolve(value, steps)?)))
+            .collect::<Result<_, _>>()
+            .map(Value::Object),
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| resolve(value, steps))
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        value => Ok(value),
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{
+    message::{RequestId, ResultMessage, WebsocketMessage},
+    subsystems::WebsocketSystem,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+/// Progress of a running task.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Progress {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Progress {
+    pub fn percent(percent: f32, message: impl Into<String>) -> Self {
+        Self {
+            percent: Some(percent),
+            message: Some(message.into()),
+            ..Self::default()
+        }
+    }
+
+    pub fn items(processed: u64, message: impl Into<String>) -> Self {
+        Self {
+            processed: Some(processed),
+            message: Some(message.into()),
+            ..Self::default()
+        }
+    }
+}
+
+/// Handle used by subsystem tasks to report progress to the client that sent the request.
+///
+/// Reports are throttled to one per `interval`, and never block: if the session channel is
+/// full the report is dropped.
+#[derive(Clone)]
+pub struct ProgressReporter {
+    /// `None` when the client did not ask for progress
+    sender: Option<mpsc::Sender<WebsocketMessage>>,
+    system: WebsocketSystem,
+    id: Option<RequestId>,
+    interval: Duration,
+    last_report: Arc<Mutex<Option<Instant>>>,
+    finished: Arc<AtomicBool>,
+}
+
+impl ProgressReporter {
+    pub fn new(
+        sender: mpsc::Sender<WebsocketMessage>,
+        system: WebsocketSystem,
+        id: Option<RequestId>,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            sender: Some(sender),
+            system,
+            id,
+            interval,
+            last_report: Arc::new(Mutex::new(None)),
+            finished: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A reporter that discards every report.
+    pub fn disabled(system: WebsocketSystem) -> Self {
+        Self {
+            sender: None,
+            system,
+            id: None,
+            interval: Duration::ZERO,
+            last_report: Arc::new(Mutex::new(None)),
+            finished: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn report(&self, progress: Progress) {
+        let sender = match &self.sender {
+            Some(sender) if !self.finished.load(Ordering::Acquire) => sender,
+            _ => return,
+        };
+        {
+            let mut last_report = self.last_report.lock().unwrap();
+            let now = Instant::now();
+            if matches!(*last_report, Some(last) if now.duration_since(last) < self.interval) {
+                return;
+            }
+            *last_report = Some(now);
+        }
+        let msg =
+            ResultMessage::from_progress(progress, Some(self.system)).with_id(self.id.clone());
+        if sender.try_send(WebsocketMessage::TaskResult(msg)).is_err() {
+            tracing::debug!("Dropped progress report.");
+        }
+    }
+
+    /// Stops sending reports, called before the final result is sent.
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Release);
+    }
+}
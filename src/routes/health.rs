@@ -0,0 +1,19 @@
+use crate::limits::{ConnectionLimiter, ConnectionUsage};
+use axum::{extract::Extension, Json};
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub status: &'static str,
+    pub connections: ConnectionUsage,
+}
+
+pub async fn health_check(
+    Extension(limiter): Extension<Arc<ConnectionLimiter>>,
+) -> Json<HealthReport> {
+    Json(HealthReport {
+        status: "ok",
+        connections: limiter.usage(),
+    })
+}
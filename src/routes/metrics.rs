@@ -0,0 +1,60 @@
+use crate::{limits::ConnectionLimiter, service::metrics::TaskMetrics};
+use axum::extract::Extension;
+use std::{fmt::Write, sync::Arc};
+
+/// Exposes metrics in the Prometheus text format.
+pub async fn metrics(
+    Extension(limiter): Extension<Arc<ConnectionLimiter>>,
+    Extension(task_metrics): Extension<Arc<TaskMetrics>>,
+) -> String {
+    let usage = limiter.usage();
+    let mut out = String::new();
+    gauge(
+        &mut out,
+        "ws_sessions_active",
+        "Active websocket sessions.",
+        usage.sessions,
+    );
+    gauge(
+        &mut out,
+        "ws_sessions_max",
+        "Maximum concurrent websocket sessions.",
+        usage.max_sessions,
+    );
+    gauge(
+        &mut out,
+        "ws_peer_ips_active",
+        "Distinct peer IPs with active sessions.",
+        usage.peer_ips,
+    );
+    gauge(
+        &mut out,
+        "ws_identities_active",
+        "Distinct identities with active sessions.",
+        usage.identities,
+    );
+    let _ = writeln!(
+        out,
+        "# HELP ws_sessions_rejected_total Rejected websocket upgrades."
+    );
+    let _ = writeln!(out, "# TYPE ws_sessions_rejected_total counter");
+    for (reason, value) in [
+        ("max_sessions", usage.rejected_total),
+        ("max_sessions_per_ip", usage.rejected_per_ip),
+        ("max_sessions_per_identity", usage.rejected_per_identity),
+    ] {
+        let _ = writeln!(
+            out,
+            "ws_sessions_rejected_total{{reason=\"{}\"}} {}",
+            reason, value
+        );
+    }
+    task_metrics.render(&mut out);
+    out
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
@@ -0,0 +1,5 @@
+mod health;
+mod metrics;
+
+pub use health::health_check;
+pub use metrics::metrics;
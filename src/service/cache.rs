@@ -0,0 +1,159 @@
+//! Caches the results of idempotent tasks, shared by the sessions of an identity.
+use super::{TaskRequest, TaskResponse};
+use crate::{configuration::CacheSettings, message::ResultMessage, subsystems::WebsocketSystem};
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{BoxError, Layer, Service};
+
+/// Identity of the session, system, task and normalized payload.
+type CacheKey = (Option<String>, WebsocketSystem, String, String);
+
+struct Entry {
+    result: ResultMessage,
+    inserted: Instant,
+    expires: Instant,
+}
+
+/// Successful results of the tasks with a configured TTL.
+pub struct ResultCache {
+    settings: CacheSettings,
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+}
+
+impl ResultCache {
+    pub fn new(settings: CacheSettings) -> Self {
+        Self {
+            settings,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn ttl(&self, system: WebsocketSystem, task: &str) -> Option<Duration> {
+        self.settings.ttl.get(&system)?.get(task).copied()
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<ResultMessage> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.result.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Stores a result, evicting expired entries and then the oldest ones when full.
+    fn insert(&self, key: CacheKey, result: ResultMessage, ttl: Duration) {
+        if self.settings.max_entries == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) && entries.len() >= self.settings.max_entries {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        while !entries.contains_key(&key) && entries.len() >= self.settings.max_entries {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.inserted)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => break,
+            };
+        }
+        entries.insert(
+            key,
+            Entry {
+                result,
+                inserted: now,
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+/// Answers tasks from the [`ResultCache`] when possible.
+///
+/// Streamed tasks are never cached. Requests with `no_cache` run the task and refresh the
+/// cached result.
+#[derive(Clone)]
+pub struct CacheLayer {
+    cache: Arc<ResultCache>,
+}
+
+impl CacheLayer {
+    pub fn new(cache: Arc<ResultCache>) -> Self {
+        Self { cache }
+    }
+}
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = Cache<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Cache {
+            inner,
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Cache<S> {
+    inner: S,
+    cache: Arc<ResultCache>,
+}
+
+impl<S> Service<TaskRequest> for Cache<S>
+where
+    S: Service<TaskRequest, Response = TaskResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = TaskResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<TaskResponse, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: TaskRequest) -> Self::Future {
+        let ttl = match self.cache.ttl(req.system, &req.task) {
+            Some(ttl) if !req.stream => ttl,
+            _ => return self.inner.call(req).boxed(),
+        };
+        // Object keys are sorted, so equivalent payloads serialize the same
+        let key = (
+            req.session.identity.clone(),
+            req.system,
+            req.task.clone(),
+            req.payload.to_string(),
+        );
+        if !req.no_cache {
+            if let Some(result) = self.cache.get(&key) {
+                tracing::debug!("Cache hit.");
+                return futures::future::ready(Ok(TaskResponse::Result(result))).boxed();
+            }
+        }
+        let cache = self.cache.clone();
+        let response = self.inner.call(req);
+        async move {
+            let response = response.await?;
+            if let TaskResponse::Result(result) = &response {
+                if result.success {
+                    cache.insert(key, result.clone(), ttl);
+                }
+            }
+            Ok(response)
+        }
+        .boxed()
+    }
+}
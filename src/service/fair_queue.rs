@@ -0,0 +1,253 @@
+//! Server-wide limits on expensive tasks, shared fairly between users.
+//!
+//! Tasks are assigned a cost class and a priority in the configuration. Each class allows a
+//! number of concurrent tasks, the others wait for a permit. Waiting tasks of a higher priority
+//! go first, and tasks of the same priority are served round-robin across users, so a user
+//! with many sessions or jobs waits for their turn like everyone else.
+use super::{TaskRequest, TaskResponse};
+use crate::{
+    configuration::{FairQueueSettings, Priority},
+    subsystems::timeout_result,
+};
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{sync::oneshot, time::timeout_at};
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FairQueueError {
+    #[error("Task {system:?}.{task} uses the undefined cost class {class}.")]
+    UnknownClass {
+        system: crate::subsystems::WebsocketSystem,
+        task: String,
+        class: String,
+    },
+}
+
+/// Waiting tasks of a priority level.
+#[derive(Default)]
+struct Level {
+    /// Users with waiting tasks, in turn order
+    turns: VecDeque<String>,
+    waiters: HashMap<String, VecDeque<oneshot::Sender<Permit>>>,
+}
+
+impl Level {
+    /// Next waiter, taking turns between users.
+    fn pop(&mut self) -> Option<oneshot::Sender<Permit>> {
+        let user = self.turns.pop_front()?;
+        let waiters = self.waiters.get_mut(&user)?;
+        let waiter = waiters.pop_front();
+        if waiters.is_empty() {
+            self.waiters.remove(&user);
+        } else {
+            self.turns.push_back(user);
+        }
+        waiter
+    }
+
+    fn push(&mut self, user: String, waiter: oneshot::Sender<Permit>) {
+        let waiters = self.waiters.entry(user.clone()).or_default();
+        if waiters.is_empty() {
+            self.turns.push_back(user);
+        }
+        waiters.push_back(waiter);
+    }
+}
+
+struct State {
+    available: usize,
+    /// Indexed by priority, highest first
+    levels: [Level; 3],
+}
+
+/// Semaphore handing its permits out by priority, then round-robin across users.
+pub struct FairSemaphore {
+    state: Mutex<State>,
+}
+
+impl FairSemaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                available: permits,
+                levels: Default::default(),
+            }),
+        }
+    }
+
+    /// Waits for a permit, `user` is the identity or session of the task.
+    pub async fn acquire(self: &Arc<Self>, user: String, priority: Priority) -> Permit {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                return Permit {
+                    semaphore: Some(self.clone()),
+                };
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.levels[priority as usize].push(user, sender);
+            receiver
+        };
+        // The sender is only dropped along with the semaphore, which `self` keeps alive
+        receiver.await.expect("Fair semaphore dropped a waiter.")
+    }
+
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let waiter = state.levels.iter_mut().find_map(Level::pop);
+            let waiter = match waiter {
+                Some(waiter) => waiter,
+                None => {
+                    state.available += 1;
+                    return;
+                }
+            };
+            let permit = Permit {
+                semaphore: Some(self.clone()),
+            };
+            match waiter.send(permit) {
+                Ok(()) => return,
+                // The waiting task was cancelled, the permit goes to the next one instead of
+                // being released, which would deadlock
+                Err(mut permit) => permit.semaphore = None,
+            }
+        }
+    }
+}
+
+/// Lets a task run, the next waiting task gets it when dropped.
+pub struct Permit {
+    /// `None` once handed over to another task
+    semaphore: Option<Arc<FairSemaphore>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(semaphore) = self.semaphore.take() {
+            semaphore.release();
+        }
+    }
+}
+
+/// Semaphores of the cost classes.
+pub struct FairQueue {
+    settings: FairQueueSettings,
+    classes: HashMap<String, Arc<FairSemaphore>>,
+}
+
+impl FairQueue {
+    pub fn new(settings: FairQueueSettings) -> Result<Self, FairQueueError> {
+        for (system, tasks) in &settings.tasks {
+            for (task, cost) in tasks {
+                if !settings.classes.contains_key(&cost.class) {
+                    return Err(FairQueueError::UnknownClass {
+                        system: *system,
+                        task: task.clone(),
+                        class: cost.class.clone(),
+                    });
+                }
+            }
+        }
+        let classes = settings
+            .classes
+            .iter()
+            .map(|(class, permits)| (class.clone(), Arc::new(FairSemaphore::new(*permits))))
+            .collect();
+        Ok(Self { settings, classes })
+    }
+
+    /// Semaphore and priority of a task, `None` if it is not limited.
+    fn semaphore(&self, req: &TaskRequest) -> Option<(Arc<FairSemaphore>, Priority)> {
+        let cost = self.settings.tasks.get(&req.system)?.get(&req.task)?;
+        let semaphore = self.classes.get(&cost.class)?;
+        Some((semaphore.clone(), cost.priority))
+    }
+}
+
+/// Runs the tasks with a cost class once their class has a free permit.
+///
+/// Waiting counts towards the deadline of the task. Streamed tasks hold their permit until the
+/// stream ends.
+#[derive(Clone)]
+pub struct FairQueueLayer {
+    queue: Arc<FairQueue>,
+}
+
+impl FairQueueLayer {
+    pub fn new(queue: Arc<FairQueue>) -> Self {
+        Self { queue }
+    }
+}
+
+impl<S> Layer<S> for FairQueueLayer {
+    type Service = FairQueueService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        FairQueueService {
+            inner,
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FairQueueService<S> {
+    inner: S,
+    queue: Arc<FairQueue>,
+}
+
+impl<S> Service<TaskRequest> for FairQueueService<S>
+where
+    S: Service<TaskRequest, Response = TaskResponse, Error = BoxError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = TaskResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<TaskResponse, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: TaskRequest) -> Self::Future {
+        let (semaphore, priority) = match self.queue.semaphore(&req) {
+            Some(limit) => limit,
+            None => return self.inner.call(req).boxed(),
+        };
+        let user = req
+            .session
+            .identity
+            .clone()
+            .unwrap_or_else(|| req.session.id.to_string());
+        // The inner service is called once a permit is available, which `self.inner` is ready for
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+        async move {
+            let system = req.system;
+            let permit = match timeout_at(req.ctx.deadline, semaphore.acquire(user, priority)).await
+            {
+                Ok(permit) => permit,
+                Err(_) => return Ok(TaskResponse::Result(timeout_result(system))),
+            };
+            match inner.oneshot(req).await? {
+                TaskResponse::Stream(stream) => Ok(TaskResponse::Stream(
+                    stream
+                        .map(move |result| {
+                            let _permit = &permit;
+                            result
+                        })
+                        .boxed(),
+                )),
+                response => Ok(response),
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,249 @@
+//! Replays the result of a request instead of running it again when a client retries it with
+//! the same idempotency key.
+use super::{TaskRequest, TaskResponse};
+use crate::{
+    configuration::IdempotencySettings,
+    message::{ErrorCode, ErrorPayload, ResultMessage},
+    subsystems::WebsocketSystem,
+};
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::sync::watch;
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+/// Identity of the session and idempotency key.
+type IdempotencyKey = (Option<String>, String);
+/// System, task and normalized payload, a key may only be reused for the same request.
+type Fingerprint = (WebsocketSystem, String, String);
+
+enum Entry {
+    /// The first request with the key is still running
+    Running {
+        fingerprint: Fingerprint,
+        done: watch::Receiver<Option<ResultMessage>>,
+    },
+    Done {
+        fingerprint: Fingerprint,
+        result: ResultMessage,
+        inserted: Instant,
+    },
+}
+
+impl Entry {
+    fn fingerprint(&self) -> &Fingerprint {
+        match self {
+            Entry::Running { fingerprint, .. } | Entry::Done { fingerprint, .. } => fingerprint,
+        }
+    }
+}
+
+/// What a request with a key does.
+enum Claim {
+    Replay(ResultMessage),
+    /// Waits for the request already running with the key
+    Wait(watch::Receiver<Option<ResultMessage>>),
+    /// Runs the task, sending its result to the requests waiting for it
+    Run(watch::Sender<Option<ResultMessage>>),
+    /// Runs the task without remembering its result, the store is full of running requests
+    Untracked,
+}
+
+/// Whether a retry gets the same result, so it can be replayed: successes and errors caused by
+/// the request itself. Timeouts and failures of the task may not happen again.
+fn replayable(result: &ResultMessage) -> bool {
+    if result.success {
+        return true;
+    }
+    match serde_json::from_value::<ErrorPayload>(result.payload.clone()) {
+        Ok(error) => matches!(
+            error.code,
+            ErrorCode::ParseError
+                | ErrorCode::InvalidRequest
+                | ErrorCode::UnknownMethod
+                | ErrorCode::UnknownTask
+        ),
+        Err(_) => false,
+    }
+}
+
+/// Whether the result of a task depends on the session that sent it, in which case it must not
+/// be replayed to another session, e.g. of another anonymous client.
+fn session_dependent(system: WebsocketSystem) -> bool {
+    matches!(system, WebsocketSystem::Session | WebsocketSystem::Jobs)
+}
+
+/// Results of the requests with an idempotency key, kept for the configured window.
+pub struct IdempotencyStore {
+    settings: IdempotencySettings,
+    entries: Mutex<HashMap<IdempotencyKey, Entry>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(settings: IdempotencySettings) -> Self {
+        Self {
+            settings,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn claim(&self, key: &IdempotencyKey, fingerprint: &Fingerprint) -> Claim {
+        let mut entries = self.entries.lock().unwrap();
+        let window = self.settings.window;
+        // The sender of a running entry is gone once its request was aborted
+        entries.retain(|_, entry| match entry {
+            Entry::Done { inserted, .. } => inserted.elapsed() < window,
+            Entry::Running { done, .. } => done.has_changed().is_ok(),
+        });
+        match entries.get(key) {
+            Some(entry) if entry.fingerprint() != fingerprint => {
+                return Claim::Replay(ResultMessage::from_error_code(
+                    ErrorCode::InvalidRequest,
+                    "Idempotency key was already used for a different request.",
+                    Some(fingerprint.0),
+                ))
+            }
+            Some(Entry::Done { result, .. }) => return Claim::Replay(result.clone()),
+            Some(Entry::Running { done, .. }) => return Claim::Wait(done.clone()),
+            None => {}
+        }
+        while entries.len() >= self.settings.max_entries {
+            let oldest = entries
+                .iter()
+                .filter_map(|(key, entry)| match entry {
+                    Entry::Done { inserted, .. } => Some((key, *inserted)),
+                    Entry::Running { .. } => None,
+                })
+                .min_by_key(|(_, inserted)| *inserted)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => return Claim::Untracked,
+            };
+        }
+        let (sender, done) = watch::channel(None);
+        entries.insert(
+            key.clone(),
+            Entry::Running {
+                fingerprint: fingerprint.clone(),
+                done,
+            },
+        );
+        Claim::Run(sender)
+    }
+
+    fn complete(&self, key: IdempotencyKey, fingerprint: Fingerprint, result: &ResultMessage) {
+        let entry = Entry::Done {
+            fingerprint,
+            result: result.clone(),
+            inserted: Instant::now(),
+        };
+        self.entries.lock().unwrap().insert(key, entry);
+    }
+
+    fn abandon(&self, key: &IdempotencyKey) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    async fn call<S>(
+        self: Arc<Self>,
+        key: IdempotencyKey,
+        inner: S,
+        req: TaskRequest,
+    ) -> Result<TaskResponse, BoxError>
+    where
+        S: Service<TaskRequest, Response = TaskResponse, Error = BoxError>,
+    {
+        let fingerprint = (req.system, req.task.clone(), req.payload.to_string());
+        loop {
+            match self.claim(&key, &fingerprint) {
+                Claim::Replay(result) => {
+                    tracing::debug!("Replaying the result of idempotency key {}.", key.1);
+                    return Ok(TaskResponse::Result(result));
+                }
+                Claim::Wait(mut done) => {
+                    if let Ok(result) = done.wait_for(Option::is_some).await {
+                        return Ok(TaskResponse::Result(result.clone().unwrap()));
+                    }
+                    // The first request was aborted, claim the key again
+                }
+                Claim::Run(sender) => {
+                    let response = inner.oneshot(req).await;
+                    match &response {
+                        Ok(TaskResponse::Result(result)) if replayable(result) => {
+                            self.complete(key, fingerprint, result);
+                            sender.send_replace(Some(result.clone()));
+                        }
+                        // Waiting requests run the task themselves
+                        _ => self.abandon(&key),
+                    }
+                    return response;
+                }
+                Claim::Untracked => return inner.oneshot(req).await,
+            }
+        }
+    }
+}
+
+/// Answers requests carrying an idempotency key with the result of the first request with the
+/// same key and identity, for the configured window.
+///
+/// Streamed tasks and the tasks of the session and jobs subsystems are not deduplicated.
+#[derive(Clone)]
+pub struct IdempotencyLayer {
+    store: Arc<IdempotencyStore>,
+}
+
+impl IdempotencyLayer {
+    pub fn new(store: Arc<IdempotencyStore>) -> Self {
+        Self { store }
+    }
+}
+
+impl<S> Layer<S> for IdempotencyLayer {
+    type Service = Idempotency<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Idempotency {
+            inner,
+            store: self.store.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Idempotency<S> {
+    inner: S,
+    store: Arc<IdempotencyStore>,
+}
+
+impl<S> Service<TaskRequest> for Idempotency<S>
+where
+    S: Service<TaskRequest, Response = TaskResponse, Error = BoxError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = TaskResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<TaskResponse, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: TaskRequest) -> Self::Future {
+        let key = match &req.idempotency_key {
+            Some(key) if !req.stream && !session_dependent(req.system) => {
+                (req.session.identity.clone(), key.clone())
+            }
+            _ => return self.inner.call(req).boxed(),
+        };
+        // The inner service may only be called once it is ready, which `self.inner` is
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+        self.store.clone().call(key, inner, req).boxed()
+    }
+}
@@ -0,0 +1,49 @@
+//! Restricts tasks to sessions opened with an identity.
+use super::{TaskRequest, TaskResponse};
+use crate::message::{ErrorCode, ResultMessage};
+use futures::future::{self, Either, Ready};
+use std::task::{Context, Poll};
+use tower::{BoxError, Layer, Service};
+
+/// Answers tasks from anonymous sessions with an `unauthorized` error instead of running them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequireIdentityLayer;
+
+impl<S> Layer<S> for RequireIdentityLayer {
+    type Service = RequireIdentity<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireIdentity { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequireIdentity<S> {
+    inner: S,
+}
+
+impl<S> Service<TaskRequest> for RequireIdentity<S>
+where
+    S: Service<TaskRequest, Response = TaskResponse, Error = BoxError>,
+{
+    type Response = TaskResponse;
+    type Error = BoxError;
+    type Future = Either<Ready<Result<TaskResponse, BoxError>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: TaskRequest) -> Self::Future {
+        if req.session.identity.is_some() {
+            return Either::Right(self.inner.call(req));
+        }
+        tracing::info!("Rejected task from anonymous session.");
+        let result = ResultMessage::from_error_code(
+            ErrorCode::Unauthorized,
+            "Task requires an authenticated session.",
+            Some(req.system),
+        );
+        Either::Left(future::ready(Ok(TaskResponse::Result(result))))
+    }
+}
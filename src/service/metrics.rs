@@ -0,0 +1,144 @@
+//! Counts tasks per subsystem and task name, for the `/metrics` endpoint.
+use super::{TaskRequest, TaskResponse};
+use crate::subsystems::WebsocketSystem;
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+use tower::{BoxError, Layer, Service};
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TaskCounters {
+    total: u64,
+    failed: u64,
+    seconds: f64,
+}
+
+/// Task counters, shared by every session.
+#[derive(Debug, Default)]
+pub struct TaskMetrics {
+    counters: Mutex<BTreeMap<(String, String), TaskCounters>>,
+}
+
+impl TaskMetrics {
+    fn record(&self, system: WebsocketSystem, task: String, failed: bool, seconds: f64) {
+        let system = serde_json::to_value(system)
+            .ok()
+            .and_then(|system| system.as_str().map(String::from))
+            .unwrap_or_default();
+        let mut counters = self.counters.lock().unwrap();
+        let counters = counters.entry((system, task)).or_default();
+        counters.total += 1;
+        counters.failed += failed as u64;
+        counters.seconds += seconds;
+    }
+
+    /// Appends the counters in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        let counters = self.counters.lock().unwrap();
+        let families = [
+            ("ws_tasks_total", "Tasks run."),
+            ("ws_tasks_failed_total", "Tasks that failed."),
+            ("ws_task_duration_seconds_sum", "Time spent running tasks."),
+        ];
+        for (name, help) in families {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for ((system, task), counters) in counters.iter() {
+                let value = match name {
+                    "ws_tasks_total" => counters.total.to_string(),
+                    "ws_tasks_failed_total" => counters.failed.to_string(),
+                    _ => counters.seconds.to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "{}{{system=\"{}\",task=\"{}\"}} {}",
+                    name,
+                    escape_label(system),
+                    escape_label(task),
+                    value
+                );
+            }
+        }
+    }
+}
+
+/// Escapes a label value as the Prometheus text format requires.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Records every task in a [`TaskMetrics`].
+#[derive(Debug, Clone)]
+pub struct MetricsLayer {
+    metrics: Arc<TaskMetrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<TaskMetrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = Metrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Metrics {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics<S> {
+    inner: S,
+    metrics: Arc<TaskMetrics>,
+}
+
+impl<S> Service<TaskRequest> for Metrics<S>
+where
+    S: Service<TaskRequest, Response = TaskResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = TaskResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<TaskResponse, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: TaskRequest) -> Self::Future {
+        let metrics = self.metrics.clone();
+        let (system, task) = (req.system, req.task.clone());
+        let start = Instant::now();
+        let response = self.inner.call(req);
+        async move {
+            let response = response.await;
+            let failed = match &response {
+                Ok(TaskResponse::Result(result)) => !result.success,
+                Ok(_) => false,
+                Err(_) => true,
+            };
+            // Client-chosen names would grow the label set without bound, whichever layer
+            // rejected them
+            let task = if system.has_task(&task) {
+                task
+            } else {
+                "unknown".into()
+            };
+            metrics.record(system, task, failed, start.elapsed().as_secs_f64());
+            response
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,361 @@
+//! Task dispatch as a `tower::Service`, so cross-cutting concerns are stacked as layers around
+//! the subsystems instead of being coded into them.
+//!
+//! Every session worker sends its tasks through a [`TaskService`]: the [`SubsystemService`] of
+//! its subsystem wrapped in the [`TaskLayers`] given when building the application.
+pub mod cache;
+pub mod fair_queue;
+pub mod idempotency;
+pub mod identity;
+pub mod metrics;
+pub mod trace;
+
+use crate::{
+    configuration::WebsocketSettings,
+    error::WebsocketError,
+    message::{ErrorCode, RequestId, ResultMessage, TaskMessage, WebsocketMessage},
+    progress::ProgressReporter,
+    session::SessionContext,
+    subsystems::{timeout_result, SessionInfo, Subsystem, TaskContext, WebsocketSystem},
+};
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{timeout, timeout_at, Instant},
+};
+use tower::{util::BoxCloneService, BoxError, Layer, Service, ServiceExt};
+
+/// A task sent by a session.
+pub struct TaskRequest {
+    pub system: WebsocketSystem,
+    pub task: String,
+    pub payload: serde_json::Value,
+    pub id: Option<RequestId>,
+    /// The result should be streamed in chunks
+    pub stream: bool,
+    /// Skip cached results
+    pub no_cache: bool,
+    /// Requests with the same key and identity get the result of the first one
+    pub idempotency_key: Option<String>,
+    pub ctx: TaskContext,
+    pub session: Arc<SessionInfo>,
+}
+
+pub enum TaskResponse {
+    Result(ResultMessage),
+    /// Partial results of a streamed task, the session sends them as chunk messages
+    Stream(BoxStream<'static, ResultMessage>),
+}
+
+/// Type-erased task service, as run by the session workers.
+pub type TaskService = BoxCloneService<TaskRequest, TaskResponse, BoxError>;
+
+/// Runs the tasks of a subsystem with the state of a session.
+pub struct SubsystemService<S: Subsystem> {
+    system: Arc<S>,
+    state: Arc<S::SessionState>,
+}
+
+impl<S: Subsystem> SubsystemService<S> {
+    pub fn new(system: Arc<S>, state: Arc<S::SessionState>) -> Self {
+        Self { system, state }
+    }
+}
+
+impl<S: Subsystem> Clone for SubsystemService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            system: self.system.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S> Service<TaskRequest> for SubsystemService<S>
+where
+    S: Subsystem + Send + Sync + 'static,
+    S::Task: DeserializeOwned + Send,
+    S::Error: std::error::Error + Send + 'static,
+{
+    type Response = TaskResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<TaskResponse, BoxError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: TaskRequest) -> Self::Future {
+        let system = self.system.clone();
+        let state = self.state.clone();
+        async move {
+            let system_id = req.system;
+            let task = match serde_json::from_str::<S::Task>(&format!("{:?}", req.task)) {
+                Ok(task) => task,
+                Err(e) => {
+                    return Ok(TaskResponse::Result(ResultMessage::from_error_code(
+                        ErrorCode::UnknownTask,
+                        format!("Failed to deserialize message: {}", e),
+                        Some(req.system),
+                    )))
+                }
+            };
+            if !req.stream {
+                let result = system.run_task(task, req.payload, req.ctx, &state).await;
+                return Ok(TaskResponse::Result(result));
+            }
+            let progress = req.ctx.progress.clone();
+            let deadline = req.ctx.deadline;
+            let handle = system.handle_message_stream(task, req.payload, req.ctx, &state);
+            let result = match timeout_at(deadline, handle).await {
+                Ok(Ok(stream)) => {
+                    let stream = stream.map(move |result| match result {
+                        Ok(res) => ResultMessage::from_json(res, Some(system_id)),
+                        Err(e) => ResultMessage::from_error(e, Some(system_id)),
+                    });
+                    return Ok(TaskResponse::Stream(stream.boxed()));
+                }
+                Ok(Err(e)) => ResultMessage::from_error(e, Some(system_id)),
+                Err(_) => timeout_result(system_id),
+            };
+            progress.finish();
+            Ok(TaskResponse::Result(result))
+        }
+        .boxed()
+    }
+}
+
+type BoxLayer = Arc<dyn Fn(TaskService) -> TaskService + Send + Sync>;
+
+/// Layers wrapped around the task services, either of every subsystem or of a single one.
+///
+/// As with `tower::ServiceBuilder`, the first layer added is the outermost one. Global layers
+/// wrap the per-subsystem ones, which wrap the nested layers.
+#[derive(Clone, Default)]
+pub struct TaskLayers {
+    global: Vec<BoxLayer>,
+    systems: HashMap<WebsocketSystem, Vec<BoxLayer>>,
+    nested: Option<Box<TaskLayers>>,
+}
+
+impl TaskLayers {
+    /// Adds a layer around the tasks of every subsystem.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<TaskService> + Send + Sync + 'static,
+        L::Service: Service<TaskRequest, Response = TaskResponse, Error = BoxError>
+            + Clone
+            + Send
+            + 'static,
+        <L::Service as Service<TaskRequest>>::Future: Send + 'static,
+    {
+        self.global.push(boxed(layer));
+        self
+    }
+
+    /// Adds a layer around the tasks of `system`.
+    pub fn layer_for<L>(mut self, system: WebsocketSystem, layer: L) -> Self
+    where
+        L: Layer<TaskService> + Send + Sync + 'static,
+        L::Service: Service<TaskRequest, Response = TaskResponse, Error = BoxError>
+            + Clone
+            + Send
+            + 'static,
+        <L::Service as Service<TaskRequest>>::Future: Send + 'static,
+    {
+        self.systems.entry(system).or_default().push(boxed(layer));
+        self
+    }
+
+    /// Adds the layers of `other` inside the ones already added.
+    pub fn append(mut self, other: TaskLayers) -> Self {
+        self.global.extend(other.global);
+        for (system, layers) in other.systems {
+            self.systems.entry(system).or_default().extend(layers);
+        }
+        match other.nested {
+            Some(nested) => self.nest(*nested),
+            None => self,
+        }
+    }
+
+    /// Adds the layers of `inner` inside every layer added, including the per-subsystem ones.
+    pub fn nest(mut self, inner: TaskLayers) -> Self {
+        self.nested = Some(Box::new(match self.nested.take() {
+            Some(nested) => nested.nest(inner),
+            None => inner,
+        }));
+        self
+    }
+
+    /// Wraps the service of `system` in its layers.
+    pub fn apply(&self, system: WebsocketSystem, service: TaskService) -> TaskService {
+        let service = match &self.nested {
+            Some(nested) => nested.apply(system, service),
+            None => service,
+        };
+        let system_layers = self.systems.get(&system).into_iter().flatten();
+        system_layers
+            .rev()
+            .chain(self.global.iter().rev())
+            .fold(service, |service, layer| layer(service))
+    }
+}
+
+fn boxed<L>(layer: L) -> BoxLayer
+where
+    L: Layer<TaskService> + Send + Sync + 'static,
+    L::Service:
+        Service<TaskRequest, Response = TaskResponse, Error = BoxError> + Clone + Send + 'static,
+    <L::Service as Service<TaskRequest>>::Future: Send + 'static,
+{
+    Arc::new(move |service| BoxCloneService::new(layer.layer(service)))
+}
+
+/// Runs the tasks a session sends to a subsystem through its service, until the session ends
+/// or the worker is unused for `worker_idle_timeout`.
+#[tracing::instrument(
+    name = "Handling subsystem message",
+    skip(service, internal_receiver, sender, settings, session)
+)]
+pub async fn handle_messages(
+    system: WebsocketSystem,
+    mut service: TaskService,
+    mut internal_receiver: mpsc::Receiver<TaskMessage>,
+    sender: mpsc::Sender<WebsocketMessage>,
+    settings: Arc<WebsocketSettings>,
+    session: SessionContext,
+) -> Result<(), WebsocketError> {
+    loop {
+        let mut msg = match timeout(settings.worker_idle_timeout, internal_receiver.recv()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break,
+            Err(_) => {
+                // Refuse new tasks but finish the queued ones, the session starts a new
+                // worker when needed
+                tracing::debug!("Worker unused, shutting down.");
+                internal_receiver.close();
+                continue;
+            }
+        };
+        tracing::debug!("Received: {:?}", msg);
+        // Results that go to a reply channel are sent whole, without progress
+        let reply = msg.reply.take();
+        let progress = if msg.progress && reply.is_none() {
+            ProgressReporter::new(
+                sender.clone(),
+                system,
+                msg.id.clone(),
+                settings.progress_interval,
+            )
+        } else {
+            ProgressReporter::disabled(system)
+        };
+        let timeout = settings.task_timeout(system);
+        let timeout = msg.timeout.map_or(timeout, |t| t.min(timeout));
+        let deadline = Instant::now() + timeout;
+        let stream = msg.stream && reply.is_none();
+        let request = TaskRequest {
+            system,
+            task: std::mem::take(&mut msg.name),
+            payload: msg.payload.take(),
+            id: msg.id.clone(),
+            stream,
+            no_cache: msg.no_cache,
+            idempotency_key: msg.idempotency_key.take(),
+            ctx: TaskContext {
+                progress: progress.clone(),
+                deadline,
+                session: Some(session.clone()),
+            },
+            session: session.info.clone(),
+        };
+        let response = match service.ready().await {
+            Ok(service) => service.call(request).await,
+            Err(e) => Err(e),
+        };
+        let result = match response {
+            Ok(TaskResponse::Stream(results)) => {
+                stream_results(system, results, msg.id, deadline, &progress, &sender).await;
+                continue;
+            }
+            Ok(TaskResponse::Result(result)) => result,
+            Err(e) => ResultMessage::from_error(e, Some(system)),
+        };
+        progress.finish();
+        let result = result.with_id(msg.id);
+        if stream {
+            send_result(&sender, result.with_chunk(0, true)).await;
+        } else {
+            send_reply(&sender, reply, result).await;
+        }
+    }
+    Ok(())
+}
+
+/// Sends streamed results as chunk messages, the last one is flagged so clients know when the
+/// result is complete.
+async fn stream_results(
+    system: WebsocketSystem,
+    mut results: BoxStream<'static, ResultMessage>,
+    id: Option<RequestId>,
+    deadline: Instant,
+    progress: &ProgressReporter,
+    sender: &mpsc::Sender<WebsocketMessage>,
+) {
+    // Keep one chunk buffered to know which one is the last
+    let mut index = 0;
+    let mut pending = None;
+    loop {
+        let result = match timeout_at(deadline, results.next()).await {
+            Ok(None) => break,
+            Ok(Some(result)) => result,
+            Err(_) => timeout_result(system),
+        };
+        let failed = !result.success;
+        if let Some(previous) = pending.replace(result) {
+            let previous = previous.with_id(id.clone()).with_chunk(index, false);
+            send_result(sender, previous).await;
+            index += 1;
+        }
+        if failed {
+            break;
+        }
+    }
+    progress.finish();
+    let last =
+        pending.unwrap_or_else(|| ResultMessage::from_json(serde_json::Value::Null, Some(system)));
+    send_result(sender, last.with_id(id).with_chunk(index, true)).await;
+}
+
+/// Sends the result to `reply` if present, otherwise to the session.
+async fn send_reply(
+    sender: &mpsc::Sender<WebsocketMessage>,
+    reply: Option<oneshot::Sender<ResultMessage>>,
+    result: ResultMessage,
+) {
+    match reply {
+        Some(reply) => {
+            if reply.send(result).is_err() {
+                tracing::debug!("Reply receiver dropped.");
+            }
+        }
+        None => send_result(sender, result).await,
+    }
+}
+
+async fn send_result(sender: &mpsc::Sender<WebsocketMessage>, result: ResultMessage) {
+    if sender
+        .send(WebsocketMessage::TaskResult(result))
+        .await
+        .is_err()
+    {
+        tracing::info!("Websocket receiver dropped.");
+    }
+}
@@ -0,0 +1,65 @@
+//! Logs every task with its outcome and duration.
+use super::{TaskRequest, TaskResponse};
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    task::{Context, Poll},
+    time::Instant,
+};
+use tower::{BoxError, Layer, Service};
+use tracing::Instrument;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskTraceLayer;
+
+impl<S> Layer<S> for TaskTraceLayer {
+    type Service = TaskTrace<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TaskTrace { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskTrace<S> {
+    inner: S,
+}
+
+impl<S> Service<TaskRequest> for TaskTrace<S>
+where
+    S: Service<TaskRequest, Response = TaskResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = TaskResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<TaskResponse, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: TaskRequest) -> Self::Future {
+        let span = tracing::info_span!(
+            "Running task",
+            system = ?req.system,
+            task = %req.task,
+            id = ?req.id,
+            session = %req.session.id,
+        );
+        let start = Instant::now();
+        let response = self.inner.call(req);
+        async move {
+            let response = response.await;
+            let elapsed = start.elapsed();
+            match &response {
+                Ok(TaskResponse::Result(result)) => {
+                    tracing::info!(success = result.success, ?elapsed, "Task finished.");
+                }
+                Ok(TaskResponse::Stream(_)) => tracing::info!(?elapsed, "Task streaming."),
+                Err(e) => tracing::warn!(error = %e, ?elapsed, "Task failed."),
+            }
+            response
+        }
+        .instrument(span)
+        .boxed()
+    }
+}
@@ -0,0 +1,151 @@
+//! Per-session context handed to subsystem tasks.
+use crate::{
+    handshake::Negotiated,
+    idle::{IdleTracker, InFlightGuard},
+    message::WebsocketMessage,
+    subsystems::SessionInfo,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::sync::mpsc;
+
+/// What a task knows about the session that sent it.
+#[derive(Clone)]
+pub struct SessionContext {
+    pub info: Arc<SessionInfo>,
+    pub store: SessionStore,
+    negotiated: Arc<RwLock<Option<Negotiated>>>,
+    /// Messages written to the socket of the session
+    outbox: Option<mpsc::Sender<WebsocketMessage>>,
+    idle: Option<IdleTracker>,
+}
+
+impl SessionContext {
+    pub fn new(info: Arc<SessionInfo>, store: SessionStore) -> Self {
+        Self {
+            info,
+            store,
+            negotiated: Arc::new(RwLock::new(None)),
+            outbox: None,
+            idle: None,
+        }
+    }
+
+    pub(crate) fn with_outbox(mut self, outbox: mpsc::Sender<WebsocketMessage>) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    pub(crate) fn with_idle(mut self, idle: IdleTracker) -> Self {
+        self.idle = Some(idle);
+        self
+    }
+
+    /// Keeps the session from being closed as idle until the guard is dropped, for tasks
+    /// pushing messages after their result was sent.
+    pub fn keep_active(&self) -> Option<InFlightGuard> {
+        self.idle.as_ref().map(IdleTracker::track)
+    }
+
+    /// Sends a message to the client outside of any task result, returns false once the
+    /// session is closed.
+    pub async fn push(&self, msg: WebsocketMessage) -> bool {
+        match &self.outbox {
+            Some(outbox) => outbox.send(msg).await.is_ok(),
+            None => false,
+        }
+    }
+
+    /// Parameters negotiated by the client hello, `None` if the client did not send one.
+    pub fn negotiated(&self) -> Option<Negotiated> {
+        self.negotiated.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_negotiated(&self, negotiated: Negotiated) {
+        *self.negotiated.write().unwrap() = Some(negotiated);
+    }
+}
+
+impl std::fmt::Debug for SessionContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionContext")
+            .field("id", &self.info.id)
+            .field("identity", &self.info.identity)
+            .finish()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionStoreError {
+    #[error("Session store is limited to {0} keys.")]
+    Full(usize),
+    #[error("Failed to convert value: {0}")]
+    InvalidValue(#[from] serde_json::Error),
+}
+
+/// Key/value store living as long as the session, shared by its tasks and the client through
+/// the `session` subsystem. Values are stored as JSON so both sides can read them.
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    values: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+    max_keys: usize,
+}
+
+impl SessionStore {
+    pub fn new(max_keys: usize) -> Self {
+        Self {
+            values: Arc::new(Mutex::new(HashMap::new())),
+            max_keys,
+        }
+    }
+
+    /// Value of `key`, `None` if missing or not a `T`.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.get_value(key)?;
+        serde_json::from_value(value).ok()
+    }
+
+    pub fn set<T: Serialize>(
+        &self,
+        key: impl Into<String>,
+        value: &T,
+    ) -> Result<(), SessionStoreError> {
+        self.set_value(key, serde_json::to_value(value)?)
+    }
+
+    pub fn get_value(&self, key: &str) -> Option<serde_json::Value> {
+        self.values.lock().unwrap().get(key).cloned()
+    }
+
+    /// Stores `value`, a `null` value removes the key.
+    pub fn set_value(
+        &self,
+        key: impl Into<String>,
+        value: serde_json::Value,
+    ) -> Result<(), SessionStoreError> {
+        let key = key.into();
+        let mut values = self.values.lock().unwrap();
+        if value.is_null() {
+            values.remove(&key);
+            return Ok(());
+        }
+        if !values.contains_key(&key) && values.len() >= self.max_keys {
+            return Err(SessionStoreError::Full(self.max_keys));
+        }
+        values.insert(key, value);
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> Option<serde_json::Value> {
+        self.values.lock().unwrap().remove(key)
+    }
+
+    /// Every key and value, as a JSON object.
+    pub fn to_json(&self) -> serde_json::Value {
+        let values = self.values.lock().unwrap();
+        serde_json::Value::Object(values.clone().into_iter().collect())
+    }
+}
@@ -0,0 +1,80 @@
+//! Graceful shutdown of the websocket sessions.
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::{watch, Notify};
+
+/// Close code sent to sessions when the server shuts down, "going away" in RFC 6455.
+pub const SHUTDOWN_CLOSE_CODE: u16 = 1001;
+
+/// Tells sessions to close when the server shuts down and waits for them to end.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    triggered: watch::Sender<bool>,
+    sessions: AtomicUsize,
+    sessions_ended: Notify,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (triggered, _) = watch::channel(false);
+        Self {
+            inner: Arc::new(Inner {
+                triggered,
+                sessions: AtomicUsize::new(0),
+                sessions_ended: Notify::new(),
+            }),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.inner.triggered.send_replace(true);
+    }
+
+    /// Completes once `trigger` has been called.
+    pub async fn triggered(&self) {
+        let mut triggered = self.inner.triggered.subscribe();
+        // Only fails if the sender is dropped, which `self` prevents
+        let _ = triggered.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Registers a session, the server waits for it until the returned guard is dropped.
+    pub fn session(&self) -> SessionGuard {
+        self.inner.sessions.fetch_add(1, Ordering::SeqCst);
+        SessionGuard {
+            shutdown: self.clone(),
+        }
+    }
+
+    /// Completes when every registered session has ended.
+    pub async fn sessions_ended(&self) {
+        loop {
+            let ended = self.inner.sessions_ended.notified();
+            if self.inner.sessions.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            ended.await;
+        }
+    }
+}
+
+/// A session that delays the server shutdown while alive.
+pub struct SessionGuard {
+    shutdown: Shutdown,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let inner = &self.shutdown.inner;
+        if inner.sessions.fetch_sub(1, Ordering::SeqCst) == 1 {
+            inner.sessions_ended.notify_waiters();
+        }
+    }
+}
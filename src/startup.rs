@@ -2,7 +2,7 @@ use axum::{
     extract::{Extension, WebSocketUpgrade},
     response::IntoResponse,
     routing::get,
-    AddExtensionLayer, Router,
+    Router,
 };
 use tower_http::{
     trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer},
@@ -12,27 +12,97 @@ use tracing::Level;
 
 use crate::{
     configuration::{Settings, WebsocketSettings},
+    graphql::{self, build_schema, handle_graphql_socket, GraphQLSchema, GraphQLSubprotocol},
+    jobs::scheduler::Scheduler,
+    jsonrpc::{self, SubprotocolRequested},
+    limits::{ConnectionGuard, ConnectionLimiter},
+    message::Protocol,
+    routes::{health_check, metrics},
+    service::{
+        cache::{CacheLayer, ResultCache},
+        fair_queue::{FairQueue, FairQueueLayer},
+        idempotency::{IdempotencyLayer, IdempotencyStore},
+        metrics::{MetricsLayer, TaskMetrics},
+        TaskLayers,
+    },
+    shutdown::Shutdown,
+    subsystems::{SessionStates, SubsystemRegistry},
     websocket::handle_socket,
 };
-use std::{net::TcpListener, sync::Arc};
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    time::Duration,
+};
 
 pub struct Application {
     listener: TcpListener,
     port: u16,
     app: Router,
+    subsystems: SubsystemRegistry,
+    shutdown: Shutdown,
+    shutdown_timeout: Duration,
+    scheduler: Scheduler,
 }
 
 impl Application {
     pub fn build(configuration: Settings) -> Result<Self, std::io::Error> {
+        Self::build_with_layers(configuration, TaskLayers::default())
+    }
+
+    /// Builds the application with `layers` around the subsystem tasks of every session.
+    ///
+    /// The layers run inside the metrics and outside the idempotency, cache and fair queue
+    /// layers, except for the ones added with [`TaskLayers::nest`], which run innermost.
+    pub fn build_with_layers(
+        configuration: Settings,
+        layers: TaskLayers,
+    ) -> Result<Self, std::io::Error> {
         // let listener = SocketAddr::new(configuration.ip, configuration.port);
         let address = format!("{}:{}", configuration.host, configuration.port);
         let listener = TcpListener::bind(&address)?;
         let port = listener.local_addr()?.port();
-        let app = build_app(configuration.websocket);
+        let shutdown_timeout = configuration.websocket.shutdown_timeout;
+        let scheduler = Scheduler::new(&configuration.subsystems.jobs.schedules)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let fair_queue = FairQueue::new(configuration.fair_queue.clone())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let task_metrics = Arc::new(TaskMetrics::default());
+        // The layers given by the caller, e.g. authorization, run before a task is answered from
+        // the cache or the idempotency store, or waits for its turn
+        let builtin = TaskLayers::default()
+            .layer(IdempotencyLayer::new(Arc::new(IdempotencyStore::new(
+                configuration.idempotency.clone(),
+            ))))
+            .layer(CacheLayer::new(Arc::new(ResultCache::new(
+                configuration.cache.clone(),
+            ))))
+            .layer(FairQueueLayer::new(Arc::new(fair_queue)));
+        let layers = TaskLayers::default()
+            .layer(MetricsLayer::new(task_metrics.clone()))
+            .nest(builtin)
+            .append(layers);
+        let subsystems =
+            SubsystemRegistry::new(configuration.subsystems.clone()).with_layers(layers);
+        subsystems
+            .jobs
+            .manager()
+            .open_store(|system| subsystems.service(system, &SessionStates::default()))?;
+        let shutdown = Shutdown::default();
+        let app = build_app(
+            configuration,
+            subsystems.clone(),
+            task_metrics,
+            shutdown.clone(),
+        );
         Ok(Self {
             listener,
             port,
             app,
+            subsystems,
+            shutdown,
+            shutdown_timeout,
+            scheduler,
         })
     }
 
@@ -40,19 +110,61 @@ impl Application {
         self.port
     }
 
+    /// Handle to stop the server, the same as receiving Ctrl-C.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Serves and runs the schedules until Ctrl-C or the shutdown handle is triggered, then
+    /// closes the open sessions and runs the subsystem shutdown hooks.
     pub async fn run_until_stopped(self) -> Result<(), hyper::Error> {
+        let shutdown = self.shutdown.clone();
+        self.scheduler
+            .start(self.subsystems.clone(), self.shutdown.clone());
         axum::Server::from_tcp(self.listener)?
-            .serve(self.app.into_make_service())
+            .serve(
+                self.app
+                    .into_make_service_with_connect_info::<SocketAddr, _>(),
+            )
+            .with_graceful_shutdown(async move {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => tracing::info!("Received Ctrl-C."),
+                    _ = shutdown.triggered() => {}
+                }
+            })
+            .await?;
+        tracing::info!("Server stopped, closing the sessions.");
+        self.shutdown.trigger();
+        let sessions_ended = self.shutdown.sessions_ended();
+        if tokio::time::timeout(self.shutdown_timeout, sessions_ended)
             .await
+            .is_err()
+        {
+            tracing::warn!("Sessions still open after {:?}.", self.shutdown_timeout);
+        }
+        self.subsystems.shutdown().await;
+        Ok(())
     }
 }
 
-fn build_app(websocket_settings: WebsocketSettings) -> Router {
-    tracing::info!("{:?}", websocket_settings);
-    let websocket_settings = Arc::new(websocket_settings);
+fn build_app(
+    configuration: Settings,
+    subsystems: SubsystemRegistry,
+    task_metrics: Arc<TaskMetrics>,
+    shutdown: Shutdown,
+) -> Router {
+    tracing::info!("{:?}", configuration.websocket);
+    let websocket_settings = Arc::new(configuration.websocket);
+    let identity_settings = Arc::new(configuration.identity);
+    let connection_limiter = Arc::new(ConnectionLimiter::new(configuration.connection_limits));
+    let graphql_schema = build_schema(websocket_settings.clone(), subsystems.clone());
 
     Router::new()
         .route("/ws", get(ws_handler))
+        .route("/jsonrpc", get(jsonrpc_ws_handler))
+        .route("/graphql", get(graphql_ws_handler))
+        .route("/health", get(health_check))
+        .route("/metrics", get(metrics))
         .layer(
             // More on TraceLayer: https://docs.rs/tower-http/0.1.1/tower_http/trace/index.html
             TraceLayer::new_for_http()
@@ -64,12 +176,84 @@ fn build_app(websocket_settings: WebsocketSettings) -> Router {
                         .latency_unit(LatencyUnit::Micros),
                 ),
         )
-        .layer(AddExtensionLayer::new(websocket_settings))
+        .layer(Extension(websocket_settings))
+        .layer(Extension(identity_settings))
+        .layer(Extension(connection_limiter))
+        .layer(Extension(graphql_schema))
+        .layer(Extension(subsystems))
+        .layer(Extension(task_metrics))
+        .layer(Extension(shutdown))
+}
+
+/// Makes the transport refuse frames and messages over the configured limit, before buffering
+/// them whole.
+fn limit_inbound(ws: WebSocketUpgrade, settings: &WebsocketSettings) -> WebSocketUpgrade {
+    let limit = settings.transport_message_limit();
+    ws.max_message_size(limit).max_frame_size(limit)
 }
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
+    SubprotocolRequested(jsonrpc_requested): SubprotocolRequested,
+    Extension(websocket_settings): Extension<Arc<WebsocketSettings>>,
+    Extension(subsystems): Extension<SubsystemRegistry>,
+    Extension(shutdown): Extension<Shutdown>,
+    // Reserved before upgrading, so rejected clients get a proper HTTP status
+    connection: ConnectionGuard,
+) -> impl IntoResponse {
+    let protocol = if jsonrpc_requested {
+        Protocol::JsonRpc
+    } else {
+        Protocol::Native
+    };
+    limit_inbound(ws, &websocket_settings)
+        .protocols([jsonrpc::SUBPROTOCOL])
+        .on_upgrade(move |socket| {
+            handle_socket(
+                socket,
+                websocket_settings,
+                subsystems,
+                connection,
+                protocol,
+                shutdown,
+            )
+        })
+}
+
+/// Same as `ws_handler` but always speaks JSON-RPC.
+async fn jsonrpc_ws_handler(
+    ws: WebSocketUpgrade,
+    Extension(websocket_settings): Extension<Arc<WebsocketSettings>>,
+    Extension(subsystems): Extension<SubsystemRegistry>,
+    Extension(shutdown): Extension<Shutdown>,
+    connection: ConnectionGuard,
+) -> impl IntoResponse {
+    limit_inbound(ws, &websocket_settings)
+        .protocols([jsonrpc::SUBPROTOCOL])
+        .on_upgrade(move |socket| {
+            handle_socket(
+                socket,
+                websocket_settings,
+                subsystems,
+                connection,
+                Protocol::JsonRpc,
+                shutdown,
+            )
+        })
+}
+
+async fn graphql_ws_handler(
+    ws: WebSocketUpgrade,
+    GraphQLSubprotocol(protocol): GraphQLSubprotocol,
     Extension(websocket_settings): Extension<Arc<WebsocketSettings>>,
+    Extension(schema): Extension<GraphQLSchema>,
+    Extension(subsystems): Extension<SubsystemRegistry>,
+    Extension(shutdown): Extension<Shutdown>,
+    connection: ConnectionGuard,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, websocket_settings))
+    limit_inbound(ws, &websocket_settings)
+        .protocols(graphql::SUBPROTOCOLS)
+        .on_upgrade(move |socket| {
+            handle_graphql_socket(socket, schema, subsystems, protocol, connection, shutdown)
+        })
 }
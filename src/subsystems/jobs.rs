@@ -0,0 +1,213 @@
+use super::{SessionInfo, Subsystem, TaskContext, WebsocketSystem};
+use crate::{
+    error::error_chain_fmt,
+    jobs::{scheduler::ScheduleResult, JobError, JobManager},
+    message::WebsocketMessage,
+    session::SessionContext,
+    telemetry::tokio_spawn,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Mutex};
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+use uuid::Uuid;
+
+#[derive(thiserror::Error)]
+pub enum JobsError {
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(#[from] serde_json::Error),
+    #[error("Invalid job id: {0}")]
+    InvalidId(#[from] uuid::Error),
+    #[error(transparent)]
+    Job(#[from] JobError),
+    #[error("Schedule {0} does not exist.")]
+    UnknownSchedule(String),
+    #[error("Subscriptions need a websocket session.")]
+    NoSession,
+}
+
+impl std::fmt::Debug for JobsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Lets clients follow the background jobs they submitted, from any session.
+pub struct JobsSystem {
+    manager: JobManager,
+}
+
+impl JobsSystem {
+    pub fn new(manager: JobManager) -> Self {
+        Self { manager }
+    }
+
+    pub fn manager(&self) -> &JobManager {
+        &self.manager
+    }
+
+    /// Forwards the finished runs of `schedule` to the session, until it closes. The session
+    /// is not idle while subscribed.
+    fn forward(&self, schedule: String, session: SessionContext) -> JoinHandle<()> {
+        let mut finished = self.manager.subscribe();
+        tokio_spawn(async move {
+            let _active = session.keep_active();
+            loop {
+                let job = match finished.recv().await {
+                    Ok(job) => job,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Subscriber missed {} finished jobs.", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                if job.info.schedule.as_deref() != Some(schedule.as_str()) {
+                    continue;
+                }
+                if let Some(result) = ScheduleResult::new(job) {
+                    if !session.push(WebsocketMessage::ScheduleResult(result)).await {
+                        return;
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Schedules a session is subscribed to, with the tasks forwarding their results.
+#[derive(Default)]
+pub struct Subscriptions {
+    forwarders: Mutex<HashMap<String, JoinHandle<()>>>,
+}
+
+impl Subscriptions {
+    fn clear(&self) {
+        for (_, forwarder) in self.forwarders.lock().unwrap().drain() {
+            forwarder.abort();
+        }
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[async_trait::async_trait]
+impl Subsystem for JobsSystem {
+    type Error = JobsError;
+    type Task = Task;
+    type SessionState = Subscriptions;
+
+    fn system(&self) -> WebsocketSystem {
+        WebsocketSystem::Jobs
+    }
+
+    async fn on_session_end(&self, _session: &SessionInfo, subscriptions: &Subscriptions) {
+        subscriptions.clear();
+    }
+
+    async fn on_server_shutdown(&self) {
+        self.manager.stop();
+        self.manager.flush().await;
+    }
+
+    #[tracing::instrument(name = "Handling Jobs message", skip(self, ctx, subscriptions))]
+    async fn handle_message(
+        &self,
+        task: Self::Task,
+        payload: serde_json::Value,
+        ctx: TaskContext,
+        subscriptions: &Subscriptions,
+    ) -> Result<serde_json::Value, Self::Error> {
+        let session = ctx.session.as_ref();
+        let identity = session.and_then(|session| session.info.identity.as_deref());
+        let job_id = || -> Result<Uuid, JobsError> {
+            let JobPayload { id } = serde_json::from_value(payload.clone())?;
+            Ok(Uuid::parse_str(&id)?)
+        };
+        let schedule = || -> Result<String, JobsError> {
+            let SchedulePayload { schedule } = serde_json::from_value(payload.clone())?;
+            let schedules = &self.manager.settings().schedules;
+            if !schedules.iter().any(|settings| settings.name == schedule) {
+                return Err(JobsError::UnknownSchedule(schedule));
+            }
+            Ok(schedule)
+        };
+        let result = match task {
+            Task::Status => serde_json::to_value(self.manager.status(job_id()?, identity)?),
+            Task::Result => {
+                let (info, result) = self.manager.result(job_id()?, identity)?;
+                Ok(serde_json::json!({
+                    "job": info,
+                    "success": result.as_ref().map(|result| result.success),
+                    "payload": result.map(|result| result.payload),
+                }))
+            }
+            Task::Cancel => {
+                let info = session.map(|session| session.info.as_ref());
+                serde_json::to_value(self.manager.cancel(job_id()?, info)?)
+            }
+            Task::List => serde_json::to_value(self.manager.list(identity)),
+            Task::History => {
+                let history = self.manager.history(&schedule()?);
+                Ok(serde_json::Value::Array(
+                    history
+                        .into_iter()
+                        .filter_map(ScheduleResult::new)
+                        .map(|result| serde_json::json!(result))
+                        .collect(),
+                ))
+            }
+            Task::Subscribe => {
+                let schedule = schedule()?;
+                let session = ctx.session.clone().ok_or(JobsError::NoSession)?;
+                let forwarder = self.forward(schedule.clone(), session);
+                let mut forwarders = subscriptions.forwarders.lock().unwrap();
+                if let Some(previous) = forwarders.insert(schedule.clone(), forwarder) {
+                    previous.abort();
+                }
+                Ok(serde_json::json!({ "schedule": schedule, "subscribed": true }))
+            }
+            Task::Unsubscribe => {
+                let schedule = schedule()?;
+                if let Some(forwarder) = subscriptions.forwarders.lock().unwrap().remove(&schedule)
+                {
+                    forwarder.abort();
+                }
+                Ok(serde_json::json!({ "schedule": schedule, "subscribed": false }))
+            }
+        };
+        Ok(result?)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Task {
+    /// Status of the job `{"id": ..}`
+    Status,
+    /// Result of the finished job `{"id": ..}`
+    Result,
+    /// Cancels the job `{"id": ..}`
+    Cancel,
+    /// Every job owned by the identity of the session
+    List,
+    /// Finished runs of the schedule `{"schedule": ..}`, oldest first
+    History,
+    /// Pushes a `schedule_result` message to the session whenever a run of the schedule
+    /// `{"schedule": ..}` finishes
+    Subscribe,
+    /// Stops the results of the schedule `{"schedule": ..}`
+    Unsubscribe,
+}
+
+#[derive(Deserialize)]
+struct JobPayload {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct SchedulePayload {
+    schedule: String,
+}
@@ -1,69 +1,273 @@
+pub mod jobs;
 pub mod pc_usage;
 pub mod python_repo;
+pub mod session;
 
+use self::{
+    jobs::JobsSystem, pc_usage::PcUsageSystem, python_repo::PythonRepoSystem,
+    session::SessionSystem,
+};
 use crate::{
-    error::WebsocketError,
-    message::{ResultMessage, TaskMessage, WebsocketMessage},
+    configuration::SubsystemSettings,
+    jobs::JobManager,
+    limits::ConnectionGuard,
+    message::{ErrorCode, Protocol, ResultMessage},
+    progress::ProgressReporter,
+    service::{SubsystemService, TaskLayers, TaskService},
+    session::SessionContext,
 };
-use anyhow::Context;
+use futures::{stream::BoxStream, StreamExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::sync::mpsc;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::time::{timeout_at, Instant};
+use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum WebsocketSystem {
     PythonRepo,
     PcUsage,
+    Session,
+    Jobs,
+}
+
+impl WebsocketSystem {
+    pub const ALL: [WebsocketSystem; 4] =
+        [Self::PythonRepo, Self::PcUsage, Self::Session, Self::Jobs];
+
+    /// Whether `task` names one of the tasks of the system.
+    pub fn has_task(self, task: &str) -> bool {
+        fn parses<T: DeserializeOwned>(task: &str) -> bool {
+            serde_json::from_value::<T>(serde_json::Value::String(task.to_string())).is_ok()
+        }
+        match self {
+            Self::PythonRepo => parses::<python_repo::Task>(task),
+            Self::PcUsage => parses::<pc_usage::Task>(task),
+            Self::Session => parses::<session::Task>(task),
+            Self::Jobs => parses::<jobs::Task>(task),
+        }
+    }
 }
 
+/// Handles available to a task while it runs.
+#[derive(Clone)]
+pub struct TaskContext {
+    pub progress: ProgressReporter,
+    /// The task is aborted if it is still running after this instant
+    pub deadline: Instant,
+    /// `None` for tasks run outside of a websocket session
+    pub session: Option<SessionContext>,
+}
+
+impl TaskContext {
+    /// Context for tasks that run outside of a session, without progress reports.
+    pub fn detached(system: WebsocketSystem, timeout: Duration) -> Self {
+        Self {
+            progress: ProgressReporter::disabled(system),
+            deadline: Instant::now() + timeout,
+            session: None,
+        }
+    }
+}
+
+/// The session a lifecycle hook is called for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub peer_ip: IpAddr,
+    pub identity: Option<String>,
+    pub protocol: Protocol,
+}
+
+impl SessionInfo {
+    pub fn new(connection: &ConnectionGuard, protocol: Protocol) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            peer_ip: connection.ip(),
+            identity: connection.identity().map(str::to_string),
+            protocol,
+        }
+    }
+}
+
+/// Per-session state of every subsystem, kept across worker restarts.
+#[derive(Default, Clone)]
+pub struct SessionStates {
+    pub python_repo: Arc<<PythonRepoSystem as Subsystem>::SessionState>,
+    pub pc_usage: Arc<<PcUsageSystem as Subsystem>::SessionState>,
+    pub session: Arc<<SessionSystem as Subsystem>::SessionState>,
+    pub jobs: Arc<<JobsSystem as Subsystem>::SessionState>,
+}
+
+/// Subsystems shared by every session, built once when the application starts.
+#[derive(Clone)]
+pub struct SubsystemRegistry {
+    pub python_repo: Arc<PythonRepoSystem>,
+    pub pc_usage: Arc<PcUsageSystem>,
+    pub session: Arc<SessionSystem>,
+    pub jobs: Arc<JobsSystem>,
+    layers: TaskLayers,
+}
+
+impl SubsystemRegistry {
+    pub fn new(settings: SubsystemSettings) -> Self {
+        Self {
+            python_repo: Arc::new(PythonRepoSystem::new(settings.python_repo)),
+            pc_usage: Arc::new(PcUsageSystem::new(settings.pc_usage)),
+            session: Arc::new(SessionSystem::new(settings.session)),
+            jobs: Arc::new(JobsSystem::new(JobManager::new(settings.jobs))),
+            layers: TaskLayers::default(),
+        }
+    }
+
+    /// Sets the layers wrapped around the task services of the sessions.
+    pub fn with_layers(mut self, layers: TaskLayers) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    /// Service running the tasks of `system` for a session.
+    pub fn service(&self, system: WebsocketSystem, states: &SessionStates) -> TaskService {
+        let service = match system {
+            WebsocketSystem::PythonRepo => TaskService::new(SubsystemService::new(
+                self.python_repo.clone(),
+                states.python_repo.clone(),
+            )),
+            WebsocketSystem::PcUsage => TaskService::new(SubsystemService::new(
+                self.pc_usage.clone(),
+                states.pc_usage.clone(),
+            )),
+            WebsocketSystem::Session => TaskService::new(SubsystemService::new(
+                self.session.clone(),
+                states.session.clone(),
+            )),
+            WebsocketSystem::Jobs => TaskService::new(SubsystemService::new(
+                self.jobs.clone(),
+                states.jobs.clone(),
+            )),
+        };
+        self.layers.apply(system, service)
+    }
+
+    /// Runs the `on_session_start` hook of every subsystem.
+    pub async fn session_started(&self, session: &SessionInfo, states: &SessionStates) {
+        self.python_repo
+            .on_session_start(session, &states.python_repo)
+            .await;
+        self.pc_usage
+            .on_session_start(session, &states.pc_usage)
+            .await;
+        self.session
+            .on_session_start(session, &states.session)
+            .await;
+        self.jobs.on_session_start(session, &states.jobs).await;
+    }
+
+    /// Runs the `on_session_end` hook of every subsystem.
+    pub async fn session_ended(&self, session: &SessionInfo, states: &SessionStates) {
+        self.python_repo
+            .on_session_end(session, &states.python_repo)
+            .await;
+        self.pc_usage
+            .on_session_end(session, &states.pc_usage)
+            .await;
+        self.session.on_session_end(session, &states.session).await;
+        self.jobs.on_session_end(session, &states.jobs).await;
+    }
+
+    /// Runs the `on_server_shutdown` hook of every subsystem.
+    pub async fn shutdown(&self) {
+        self.python_repo.on_server_shutdown().await;
+        self.pc_usage.on_server_shutdown().await;
+        self.session.on_server_shutdown().await;
+        self.jobs.on_server_shutdown().await;
+    }
+}
+
+/// Stream of partial results of a task.
+pub type TaskStream<E> = BoxStream<'static, Result<serde_json::Value, E>>;
+
+/// A subsystem is built once and shared by every session, so state shared between sessions
+/// lives in the subsystem itself. State for a single session goes in `SessionState`, which is
+/// created for every session.
+///
+/// The lifecycle hooks do nothing by default. `on_session_end` is called for every session that
+/// started, even when it ended on an error, and is the place to release per-session resources.
 #[async_trait::async_trait]
 pub trait Subsystem {
     type Error;
     type Task;
+    /// Use `()` for subsystems without per-session state
+    type SessionState: Default + Send + Sync + 'static;
 
     fn system(&self) -> WebsocketSystem;
 
+    /// Called when a session opens, before it sends any task.
+    async fn on_session_start(&self, _session: &SessionInfo, _state: &Self::SessionState) {}
+
+    /// Called when a session closes, once its running tasks are aborted.
+    async fn on_session_end(&self, _session: &SessionInfo, _state: &Self::SessionState) {}
+
+    /// Called once when the server stops, after the sessions have ended.
+    async fn on_server_shutdown(&self) {}
+
     async fn handle_message(
         &self,
         task: Self::Task,
         payload: serde_json::Value,
+        ctx: TaskContext,
+        session: &Self::SessionState,
     ) -> Result<serde_json::Value, Self::Error>;
 
-    #[tracing::instrument(
-        name = "Handling subsystem message",
-        skip(self, internal_receiver, sender),
-		fields(subsystem=tracing::field::Empty)
-    )]
-    async fn handle_messages(
+    /// Handles a message whose result should be streamed to the client in chunks.
+    ///
+    /// Defaults to a single chunk with the result of `handle_message`.
+    async fn handle_message_stream(
         &self,
-        mut internal_receiver: mpsc::Receiver<TaskMessage>,
-        sender: mpsc::Sender<WebsocketMessage>,
-    ) -> Result<(), WebsocketError>
+        task: Self::Task,
+        payload: serde_json::Value,
+        ctx: TaskContext,
+        session: &Self::SessionState,
+    ) -> Result<TaskStream<Self::Error>, Self::Error>
     where
-        Self: Sized,
-        Self::Task: DeserializeOwned + Send,
-        Self::Error: std::error::Error,
+        Self::Task: Send + 'async_trait,
+        Self::Error: Send + 'static,
     {
-        tracing::Span::current().record("subsystem", &tracing::field::debug(self.system()));
-        while let Some(msg) = internal_receiver.recv().await {
-            tracing::debug!("Received: {:?}", msg);
-            let result = match serde_json::from_str::<Self::Task>(&format!("{:?}", msg.name))
-                .context("Failed to deserialize message.")
-            {
-                Ok(task) => match self.handle_message(task, msg.payload).await {
-                    Ok(res) => ResultMessage::from_json(res, Some(self.system())),
-                    Err(e) => ResultMessage::from_error(e, Some(self.system())),
-                },
-                Err(e) => ResultMessage::from_error(e, Some(self.system())),
-            };
-            if sender
-                .send(WebsocketMessage::TaskResult(result))
-                .await
-                .is_err()
-            {
-                tracing::info!("Websocket receiver dropped.");
-            }
-        }
-        Ok(())
+        let result = self.handle_message(task, payload, ctx, session).await?;
+        Ok(futures::stream::once(async move { Ok(result) }).boxed())
     }
+
+    /// Runs a task until it completes or its deadline passes.
+    async fn run_task(
+        &self,
+        task: Self::Task,
+        payload: serde_json::Value,
+        ctx: TaskContext,
+        session: &Self::SessionState,
+    ) -> ResultMessage
+    where
+        Self::Task: Send + 'async_trait,
+        Self::Error: std::error::Error + Send + 'static,
+    {
+        let progress = ctx.progress.clone();
+        let deadline = ctx.deadline;
+        let handle = self.handle_message(task, payload, ctx, session);
+        let result = match timeout_at(deadline, handle).await {
+            Ok(Ok(res)) => ResultMessage::from_json(res, Some(self.system())),
+            Ok(Err(e)) => ResultMessage::from_error(e, Some(self.system())),
+            Err(_) => timeout_result(self.system()),
+        };
+        progress.finish();
+        result
+    }
+}
+
+/// Result of a task aborted at its deadline.
+pub(crate) fn timeout_result(system: WebsocketSystem) -> ResultMessage {
+    tracing::info!("Task timed out.");
+    ResultMessage::from_error_code(
+        ErrorCode::Timeout,
+        "Task exceeded its deadline and was aborted.",
+        Some(system),
+    )
 }
@@ -1,8 +1,9 @@
-use super::{Subsystem, WebsocketSystem};
-use crate::error::error_chain_fmt;
+use super::{Subsystem, TaskContext, WebsocketSystem};
+use crate::{configuration::PcUsageSettings, error::error_chain_fmt, progress::Progress};
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use systemstat::Platform;
+use tokio::{sync::Mutex, time::Instant};
 
 #[derive(thiserror::Error)]
 pub enum PcUsageError {
@@ -16,25 +17,55 @@ impl std::fmt::Debug for PcUsageError {
     }
 }
 
-pub struct PcUsageSystem;
+pub struct PcUsageSystem {
+    settings: PcUsageSettings,
+    /// Latest cpu load sample, shared by every session
+    last_sample: Mutex<Option<(Instant, serde_json::Value)>>,
+}
+
+impl PcUsageSystem {
+    pub fn new(settings: PcUsageSettings) -> Self {
+        Self {
+            settings,
+            last_sample: Mutex::new(None),
+        }
+    }
+
+    /// Returns the latest sample if it is recent enough, otherwise takes a new one. Concurrent
+    /// callers wait for the sample in progress instead of taking their own.
+    async fn cpu_load(&self, ctx: TaskContext) -> Result<serde_json::Value, PcUsageError> {
+        let mut last_sample = self.last_sample.lock().await;
+        if let Some((taken, sample)) = last_sample.as_ref() {
+            if taken.elapsed() < self.settings.sample_ttl {
+                return Ok(sample.clone());
+            }
+        }
+        let sample = get_cpu_load(ctx, self.settings.sample_duration).await?;
+        *last_sample = Some((Instant::now(), sample.clone()));
+        Ok(sample)
+    }
+}
 
 #[async_trait::async_trait]
 impl Subsystem for PcUsageSystem {
     type Error = PcUsageError;
     type Task = Task;
+    type SessionState = ();
 
     fn system(&self) -> WebsocketSystem {
         WebsocketSystem::PcUsage
     }
 
-    #[tracing::instrument(name = "Handling PcUsage message", skip(self))]
+    #[tracing::instrument(name = "Handling PcUsage message", skip(self, ctx))]
     async fn handle_message(
         &self,
         task: Self::Task,
         _payload: serde_json::Value,
+        ctx: TaskContext,
+        _session: &(),
     ) -> Result<serde_json::Value, Self::Error> {
         match task {
-            Task::CpuLoad => get_cpu_load().await,
+            Task::CpuLoad => self.cpu_load(ctx).await,
         }
     }
 }
@@ -51,13 +82,18 @@ pub struct CpuLoadResult {
     pub system: f32,
 }
 
-#[tracing::instrument(name = "Handle task GetCpuLoad")]
-async fn get_cpu_load() -> Result<serde_json::Value, PcUsageError> {
+#[tracing::instrument(name = "Handle task GetCpuLoad", skip(ctx))]
+async fn get_cpu_load(
+    ctx: TaskContext,
+    sample_duration: std::time::Duration,
+) -> Result<serde_json::Value, PcUsageError> {
     let sys = systemstat::System::new();
     let cpu = sys
         .cpu_load()
         .context("Failed to initialize cpu load reader.")?;
-    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    ctx.progress
+        .report(Progress::percent(0.0, "Sampling cpu load."));
+    tokio::time::sleep(sample_duration).await;
     let cpu_load = cpu
         .done()
         .context("Failed to read cpu load.")?
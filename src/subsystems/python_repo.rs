@@ -1,14 +1,29 @@
-use super::{Subsystem, WebsocketSystem};
-use crate::error::error_chain_fmt;
+use super::{Subsystem, TaskContext, TaskStream, WebsocketSystem};
+use crate::{configuration::PythonRepoSettings, error::error_chain_fmt, progress::Progress};
 use anyhow::Context;
-use glob::glob;
+use futures::StreamExt;
 use serde::Deserialize;
-use std::path::Path;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::spawn_blocking,
+};
 
 #[derive(thiserror::Error)]
 pub enum PythonRepoError {
     #[error("Invalid path: {0:?}")]
     InvalidPath(String),
+    #[error("The task was cancelled.")]
+    Cancelled,
+    #[error("The task exceeded its deadline.")]
+    DeadlineExceeded,
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -19,25 +34,55 @@ impl std::fmt::Debug for PythonRepoError {
     }
 }
 
-pub struct PythonRepoSystem;
+/// Walks the filesystem on the blocking pool, `settings.blocking_walks` at a time.
+pub struct PythonRepoSystem {
+    settings: PythonRepoSettings,
+    walks: Arc<Semaphore>,
+}
+
+impl PythonRepoSystem {
+    pub fn new(settings: PythonRepoSettings) -> Self {
+        let walks = Arc::new(Semaphore::new(settings.blocking_walks.max(1)));
+        Self { settings, walks }
+    }
+}
 
 #[async_trait::async_trait]
 impl Subsystem for PythonRepoSystem {
     type Error = PythonRepoError;
     type Task = Task;
+    type SessionState = ();
 
     fn system(&self) -> WebsocketSystem {
         WebsocketSystem::PythonRepo
     }
 
-    #[tracing::instrument(name = "Handling PythonRepo message", skip(self))]
+    #[tracing::instrument(name = "Handling PythonRepo message", skip(self, ctx))]
     async fn handle_message(
         &self,
         task: Self::Task,
         payload: serde_json::Value,
+        ctx: TaskContext,
+        _session: &(),
     ) -> Result<serde_json::Value, Self::Error> {
         match task {
-            Task::GetFiles => get_files(payload),
+            Task::GetFiles => get_files(payload, ctx, self.walks.clone()).await,
+        }
+    }
+
+    #[tracing::instrument(name = "Handling PythonRepo streamed message", skip(self, ctx))]
+    async fn handle_message_stream(
+        &self,
+        task: Self::Task,
+        payload: serde_json::Value,
+        ctx: TaskContext,
+        _session: &(),
+    ) -> Result<TaskStream<Self::Error>, Self::Error> {
+        match task {
+            Task::GetFiles => {
+                let chunk_size = self.settings.chunk_size;
+                get_files_stream(payload, ctx, chunk_size, self.walks.clone()).await
+            }
         }
     }
 }
@@ -48,19 +93,175 @@ pub enum Task {
     GetFiles,
 }
 
-#[tracing::instrument(name = "GetFiles task")]
-fn get_files(payload: serde_json::Value) -> Result<serde_json::Value, PythonRepoError> {
+fn repo_path(payload: &serde_json::Value) -> Result<&str, PythonRepoError> {
     let path = payload.as_str().unwrap_or("");
     if !Path::new(path).exists() {
         return Err(PythonRepoError::InvalidPath(path.into()));
     }
+    Ok(path)
+}
+
+/// Calls `found` with every python file under the path, until it returns `false`.
+///
+/// Runs on the blocking pool, checking before every directory entry whether the task was
+/// cancelled or its deadline passed, so an abandoned walk stops mid-traversal even in trees
+/// without python files.
+fn walk(
+    payload: &serde_json::Value,
+    ctx: &TaskContext,
+    cancelled: impl Fn() -> bool,
+    mut found: impl FnMut(PathBuf) -> bool,
+) -> Result<(), PythonRepoError> {
+    let deadline = ctx.deadline.into_std();
+    let check = || {
+        if cancelled() {
+            return Err(PythonRepoError::Cancelled);
+        }
+        if std::time::Instant::now() >= deadline {
+            return Err(PythonRepoError::DeadlineExceeded);
+        }
+        Ok(())
+    };
+    let mut count = 0;
+    let mut directories = vec![PathBuf::from(repo_path(payload)?)];
+    while let Some(directory) = directories.pop() {
+        check()?;
+        // Unreadable directories are skipped, as are the entries that fail
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        let mut files = Vec::new();
+        let mut subdirectories = Vec::new();
+        for entry in entries {
+            check()?;
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            // Symbolic links are not followed, so the walk cannot loop
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => subdirectories.push(entry.path()),
+                Ok(kind)
+                    if kind.is_file() && entry.file_name().to_string_lossy().ends_with(".py") =>
+                {
+                    files.push(entry.path())
+                }
+                _ => {}
+            }
+        }
+        files.sort();
+        for file in files {
+            count += 1;
+            ctx.progress.report(Progress::items(count, "Found files."));
+            if !found(file) {
+                return Ok(());
+            }
+        }
+        // Popped in order
+        subdirectories.sort_by(|a, b| b.cmp(a));
+        directories.extend(subdirectories);
+    }
+    Ok(())
+}
 
-    let files = glob(&format!("{}/**/*.py", path))
-        .context("Failed to perform glob on path.")?
-        .filter_map(Result::ok)
-        .collect::<Vec<_>>();
+/// Flags a walk as cancelled when the task is dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+#[tracing::instrument(name = "GetFiles task", skip(ctx, walks))]
+async fn get_files(
+    payload: serde_json::Value,
+    ctx: TaskContext,
+    walks: Arc<Semaphore>,
+) -> Result<serde_json::Value, PythonRepoError> {
+    // Held by the blocking thread, so the limit covers walks still stopping after a cancellation
+    let permit = walks
+        .acquire_owned()
+        .await
+        .context("Blocking pool was closed.")?;
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _guard = CancelOnDrop(cancelled.clone());
+    let files = spawn_blocking(move || {
+        let _permit = permit;
+        let mut files = Vec::new();
+        walk(
+            &payload,
+            &ctx,
+            || cancelled.load(Ordering::Acquire),
+            |file| {
+                files.push(file);
+                true
+            },
+        )?;
+        Ok::<_, PythonRepoError>(files)
+    })
+    .await
+    .context("File walk panicked.")?;
+    let files = match files {
+        // The walk can notice the deadline before the task timeout does, which reports it
+        Err(PythonRepoError::DeadlineExceeded) => return futures::future::pending().await,
+        files => files?,
+    };
 
     let result =
         serde_json::to_value(files).context("Failed to convert message to JSON format.")?;
     Ok(result)
 }
+
+#[tracing::instrument(name = "GetFiles streamed task", skip(ctx, walks))]
+async fn get_files_stream(
+    payload: serde_json::Value,
+    ctx: TaskContext,
+    chunk_size: usize,
+    walks: Arc<Semaphore>,
+) -> Result<TaskStream<PythonRepoError>, PythonRepoError> {
+    // An invalid path fails the task instead of its first chunk
+    repo_path(&payload)?;
+    let permit = walks
+        .acquire_owned()
+        .await
+        .context("Blocking pool was closed.")?;
+    let (sender, receiver) = mpsc::channel(1);
+    spawn_blocking(move || {
+        let _permit = permit;
+        let chunk_size = chunk_size.max(1);
+        let mut chunk = Vec::with_capacity(chunk_size);
+        let send = |chunk: Vec<PathBuf>| {
+            let result = serde_json::to_value(chunk)
+                .context("Failed to convert message to JSON format.")
+                .map_err(PythonRepoError::from);
+            sender.blocking_send(result).is_ok()
+        };
+        // The walk stops once the stream is dropped
+        let walked = walk(
+            &payload,
+            &ctx,
+            || sender.is_closed(),
+            |file| {
+                chunk.push(file);
+                chunk.len() < chunk_size || send(std::mem::take(&mut chunk))
+            },
+        );
+        match walked {
+            Ok(()) if !chunk.is_empty() => {
+                send(chunk);
+            }
+            Ok(()) => {}
+            Err(e) => {
+                let _ = sender.blocking_send(Err(e));
+            }
+        }
+    });
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        let result = receiver.recv().await?;
+        Some((result, receiver))
+    })
+    .boxed();
+    Ok(stream)
+}
@@ -0,0 +1,104 @@
+use super::{Subsystem, TaskContext, WebsocketSystem};
+use crate::{configuration::SessionSettings, error::error_chain_fmt, session::SessionStoreError};
+use serde::Deserialize;
+
+#[derive(thiserror::Error)]
+pub enum SessionError {
+    #[error("Task must be sent from a websocket session.")]
+    NoSession,
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(#[from] serde_json::Error),
+    #[error(transparent)]
+    Store(#[from] SessionStoreError),
+}
+
+impl std::fmt::Debug for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Lets clients read and write the key/value store of their session.
+pub struct SessionSystem {
+    settings: SessionSettings,
+}
+
+impl SessionSystem {
+    pub fn new(settings: SessionSettings) -> Self {
+        Self { settings }
+    }
+
+    /// Maximum number of keys in the store of a session.
+    pub fn max_keys(&self) -> usize {
+        self.settings.max_keys
+    }
+}
+
+#[async_trait::async_trait]
+impl Subsystem for SessionSystem {
+    type Error = SessionError;
+    type Task = Task;
+    type SessionState = ();
+
+    fn system(&self) -> WebsocketSystem {
+        WebsocketSystem::Session
+    }
+
+    #[tracing::instrument(name = "Handling Session message", skip(self, ctx))]
+    async fn handle_message(
+        &self,
+        task: Self::Task,
+        payload: serde_json::Value,
+        ctx: TaskContext,
+        _session: &(),
+    ) -> Result<serde_json::Value, Self::Error> {
+        let session = ctx.session.ok_or(SessionError::NoSession)?;
+        let store = &session.store;
+        match task {
+            Task::Set => {
+                let SetPayload { key, value } = serde_json::from_value(payload)?;
+                store.set_value(key, value)?;
+                Ok(serde_json::Value::Null)
+            }
+            Task::Get => {
+                let GetPayload { key } = match payload {
+                    serde_json::Value::Null => GetPayload::default(),
+                    payload => serde_json::from_value(payload)?,
+                };
+                Ok(match key {
+                    Some(key) => store.get_value(&key).unwrap_or_default(),
+                    None => store.to_json(),
+                })
+            }
+            Task::Info => Ok(serde_json::json!({
+                "id": session.info.id,
+                "identity": session.info.identity,
+                "negotiated": session.negotiated(),
+            })),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Task {
+    /// Sets `{"key": .., "value": ..}`, a `null` value removes the key
+    Set,
+    /// Returns the value of `{"key": ..}`, or every value without a key
+    Get,
+    /// Returns the session ID, identity and negotiated parameters
+    Info,
+}
+
+#[derive(Deserialize)]
+struct SetPayload {
+    key: String,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct GetPayload {
+    key: Option<String>,
+}
@@ -1,198 +1,721 @@
 use crate::{
     configuration::WebsocketSettings,
     error::WebsocketError,
-    message::{ClientMessage, ResultMessage, TaskMessage, WebsocketMessage},
-    subsystems::{
-        pc_usage::PcUsageSystem, python_repo::PythonRepoSystem, Subsystem, WebsocketSystem,
+    handshake::{ClientHello, HandshakeError, HandshakeMessage, Negotiated, ServerHello},
+    heartbeat::{ping_payload, pong_rtt, LatencyStats, PongMessage},
+    idle::{IdleTracker, IdleWarning, IDLE_CLOSE_CODE},
+    jsonrpc,
+    limits::ConnectionGuard,
+    message::{
+        BatchResultMessage, ClientFrame, ClientMessage, ErrorCode, Protocol, ResultMessage,
+        TaskMessage, WebsocketMessage,
     },
+    pipeline::{run_pipeline, PipelineRequest},
+    progress::ProgressReporter,
+    service::{self, TaskRequest},
+    session::{SessionContext, SessionStore},
+    shutdown::{Shutdown, SHUTDOWN_CLOSE_CODE},
+    subsystems::{SessionInfo, SessionStates, SubsystemRegistry, TaskContext, WebsocketSystem},
     telemetry::tokio_spawn,
 };
 use anyhow::Context;
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use futures::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
+    future::BoxFuture,
+    stream::{FuturesUnordered, StreamExt},
+    FutureExt,
 };
-use std::{
-    sync::{Arc, Mutex},
-    time::Instant,
+use std::{collections::HashMap, panic::AssertUnwindSafe, sync::Arc, time::Instant};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{interval, interval_at, Interval},
 };
-use tokio::sync::mpsc;
 
 pub struct Session {
-    hb: Mutex<Instant>,
-    settings: WebsocketSettings,
+    hb: Instant,
+    settings: Arc<WebsocketSettings>,
+    protocol: Protocol,
+    /// Set once the client hello is accepted
+    negotiated: Option<Negotiated>,
+    /// Reference for the timestamps in ping payloads
+    started: Instant,
+    latency: LatencyStats,
+    idle: IdleTracker,
+    idle_warned: bool,
 }
 
 impl Session {
-    pub fn new(settings: &WebsocketSettings) -> Self {
+    pub fn new(settings: Arc<WebsocketSettings>, protocol: Protocol) -> Self {
         Session {
-            hb: Mutex::new(Instant::now()),
-            settings: settings.clone(),
+            hb: Instant::now(),
+            settings,
+            protocol,
+            negotiated: None,
+            started: Instant::now(),
+            latency: LatencyStats::default(),
+            idle: IdleTracker::default(),
+            idle_warned: false,
         }
     }
 
-    /// Sends ping to client every x seconds.
-    /// Also checks heartbeats from client.
-    #[tracing::instrument(name = "Heartbeat task", level = "trace", skip(self, sender))]
-    async fn hb(&self, sender: mpsc::Sender<WebsocketMessage>) -> Result<(), WebsocketError> {
-        let mut interval = tokio::time::interval(self.settings.heartbeat_interval);
-        loop {
-            interval.tick().await;
-            // Check client heartbeats
-            if Instant::now().duration_since(*self.hb.lock().unwrap())
-                > self.settings.client_timeout
-            {
-                // Heartbeat timed out
-                tracing::info!("Websocket client heartbeat failed, disconnecting.");
-                sender.send(WebsocketMessage::Close).await?;
-                return Ok(());
+    /// Parameters in use, the configured defaults until the client sends a hello.
+    pub fn negotiated(&self) -> Negotiated {
+        self.negotiated
+            .clone()
+            .unwrap_or_else(|| Negotiated::defaults(&self.settings))
+    }
+
+    /// Round-trip times measured from the client pongs.
+    pub fn latency(&self) -> &LatencyStats {
+        &self.latency
+    }
+
+    /// Refreshes the client heartbeat.
+    fn beat(&mut self) {
+        self.hb = Instant::now();
+    }
+
+    fn record_pong(&mut self, payload: &[u8]) {
+        self.beat();
+        if let Some(rtt) = pong_rtt(self.started, payload) {
+            tracing::trace!("Round-trip time: {:?}", rtt);
+            self.latency.record(rtt);
+        }
+    }
+
+    /// Negotiates the session parameters, a client may only do it once.
+    fn negotiate(&mut self, hello: ClientHello) -> Result<Negotiated, HandshakeError> {
+        if self.negotiated.is_some() {
+            return Err(HandshakeError::AlreadyNegotiated);
+        }
+        let negotiated = Negotiated::negotiate(&self.settings, hello)?;
+        self.negotiated = Some(negotiated.clone());
+        Ok(negotiated)
+    }
+
+    /// Checks heartbeats and requests from the client, returning the message to send: a ping,
+    /// an idle warning or a close.
+    fn hb(&mut self) -> WebsocketMessage {
+        let negotiated = self.negotiated();
+        if self.hb.elapsed() > negotiated.client_timeout {
+            tracing::info!("Websocket client heartbeat failed, disconnecting.");
+            return WebsocketMessage::Close(None);
+        }
+        // Running tasks keep the session active
+        let (idle_timeout, idle_warning) = (self.settings.idle_timeout, self.settings.idle_warning);
+        match self.idle.idle_for() {
+            Some(idle) if idle >= idle_timeout => {
+                tracing::info!("Websocket client idle for {:?}, disconnecting.", idle);
+                return WebsocketMessage::Close(Some(CloseFrame {
+                    code: IDLE_CLOSE_CODE,
+                    reason: "Idle timeout.".into(),
+                }));
+            }
+            Some(idle) if idle + idle_warning >= idle_timeout => {
+                if !self.idle_warned {
+                    self.idle_warned = true;
+                    let closes_in = idle_timeout - idle;
+                    return WebsocketMessage::IdleWarning(IdleWarning { closes_in });
+                }
             }
-            // Send ping
-            tracing::trace!("Sending ping.");
-            sender.send(WebsocketMessage::Ping(vec![])).await?;
+            _ => self.idle_warned = false,
         }
+        tracing::trace!("Sending ping.");
+        WebsocketMessage::Ping(ping_payload(self.started))
     }
 }
 
-#[tracing::instrument(name = "Handling websocket message", skip(socket, settings))]
-pub async fn handle_socket(socket: WebSocket, settings: Arc<WebsocketSettings>) {
-    let session = Arc::new(Session::new(&settings));
-    let (socket_sender, socket_receiver) = socket.split();
-    let (tx, rx) = mpsc::channel(32);
-
-    let mut recv_task = tokio_spawn(receive_message(rx, socket_sender));
-    let mut hb_task = tokio_spawn({
-        let tx = tx.clone();
-        let session = session.clone();
-        async move { session.hb(tx).await }
-    });
-
-    let python_repo_system = PythonRepoSystem {};
-    let (python_repo_tx, python_repo_rx) = mpsc::channel(32);
-    let mut python_repo_task = tokio_spawn({
-        let tx = tx.clone();
-        async move { python_repo_system.handle_messages(python_repo_rx, tx).await }
-    });
-
-    let pc_usage_system = PcUsageSystem {};
-    let (pc_usage_tx, pc_usage_rx) = mpsc::channel(32);
-    let mut pc_usage_task = tokio_spawn({
-        let tx = tx.clone();
-        async move { pc_usage_system.handle_messages(pc_usage_rx, tx).await }
-    });
-
-    let mut client_recv_task = tokio_spawn({
-        let session = session.clone();
-        let tx = tx.clone();
-        async move {
-            client_receive_task(socket_receiver, session, tx, python_repo_tx, pc_usage_tx).await
-        }
-    });
-
-    let (result, _, _) = futures::future::select_all(vec![
-        &mut client_recv_task,
-        &mut recv_task,
-        &mut python_repo_task,
-        &mut pc_usage_task,
-        &mut hb_task,
-    ])
-    .await;
-
-    match result {
+#[tracing::instrument(
+    name = "Handling websocket message",
+    skip(socket, settings, subsystems, connection, shutdown),
+    fields(peer_ip = %connection.ip(), identity = ?connection.identity())
+)]
+pub async fn handle_socket(
+    socket: WebSocket,
+    settings: Arc<WebsocketSettings>,
+    subsystems: SubsystemRegistry,
+    connection: ConnectionGuard,
+    protocol: Protocol,
+    shutdown: Shutdown,
+) {
+    // Held until the end hooks ran, so the server waits for them when shutting down
+    let _session = shutdown.session();
+    let info = Arc::new(SessionInfo::new(&connection, protocol));
+    let states = SessionStates::default();
+    subsystems.session_started(&info, &states).await;
+    let store = SessionStore::new(subsystems.session.max_keys());
+    let driver = Driver::new(
+        socket,
+        settings,
+        subsystems.clone(),
+        states.clone(),
+        SessionContext::new(info.clone(), store),
+        shutdown,
+    );
+    // A panicking session must still release its resources
+    match AssertUnwindSafe(driver.run()).catch_unwind().await {
+        Ok(Ok(())) => {}
         Ok(Err(e)) => tracing::info!("Got WebsocketError: {:?}", e),
-        Err(e) => tracing::info!("Got JoinError: {:?}", e),
-        _ => {}
+        Err(_) => tracing::error!("Websocket session panicked."),
     }
+    subsystems.session_ended(&info, &states).await;
+    // Releases the session slot
+    drop(connection);
 }
 
-#[tracing::instrument(
-    name = "Client receiver task",
-    level = "trace",
-    skip(socket_receiver, session, sender, python_repo_tx)
-)]
-async fn client_receive_task(
-    mut socket_receiver: SplitStream<WebSocket>,
-    session: Arc<Session>,
-    sender: mpsc::Sender<WebsocketMessage>,
-    python_repo_tx: mpsc::Sender<TaskMessage>,
-    pc_usage_tx: mpsc::Sender<TaskMessage>,
-) -> Result<(), WebsocketError> {
-    while let Some(msg) = socket_receiver.next().await {
+/// Runs a session in a single task, multiplexing the socket, the heartbeat timer and the
+/// results of the subsystem workers.
+struct Driver {
+    socket: WebSocket,
+    session: Session,
+    heartbeat: Interval,
+    /// Results from the subsystem workers
+    results: mpsc::Receiver<WebsocketMessage>,
+    /// Combined batches waiting for their results
+    batches: FuturesUnordered<BoxFuture<'static, BatchResultMessage>>,
+    subsystems: SubsystemSenders,
+    shutdown: Shutdown,
+    closing: bool,
+}
+
+impl Driver {
+    fn new(
+        socket: WebSocket,
+        settings: Arc<WebsocketSettings>,
+        registry: SubsystemRegistry,
+        states: SessionStates,
+        context: SessionContext,
+        shutdown: Shutdown,
+    ) -> Self {
+        let session = Session::new(settings.clone(), context.info.protocol);
+        let (tx, results) = mpsc::channel(32);
+        let context = context
+            .with_outbox(tx.clone())
+            .with_idle(session.idle.clone());
+        let subsystems = SubsystemSenders::new(
+            registry,
+            states,
+            context,
+            tx,
+            settings.clone(),
+            session.idle.clone(),
+        );
+        Self {
+            socket,
+            session,
+            heartbeat: interval(settings.heartbeat_interval),
+            results,
+            batches: FuturesUnordered::new(),
+            subsystems,
+            shutdown,
+            closing: false,
+        }
+    }
+
+    async fn run(mut self) -> Result<(), WebsocketError> {
+        // JSON-RPC clients only expect responses
+        if self.session.protocol == Protocol::Native {
+            let hello = HandshakeMessage::Hello(ServerHello::new(&self.session.settings));
+            self.write(WebsocketMessage::Handshake(hello)).await?;
+        }
+        while !self.closing {
+            tokio::select! {
+                msg = self.socket.recv() => match msg {
+                    Some(Ok(msg)) => self.handle_client_message(msg).await?,
+                    Some(Err(e)) => {
+                        tracing::info!("Client disconnected: {:?}", e);
+                        break;
+                    }
+                    None => break,
+                },
+                Some(msg) = self.results.recv() => self.write(msg).await?,
+                Some(batch) = self.batches.next(), if !self.batches.is_empty() => {
+                    self.write(WebsocketMessage::BatchResult(batch)).await?;
+                }
+                _ = self.heartbeat.tick() => {
+                    let msg = self.session.hb();
+                    self.write(msg).await?;
+                }
+                _ = self.shutdown.triggered() => {
+                    tracing::info!("Server shutting down, closing the websocket.");
+                    let frame = CloseFrame {
+                        code: SHUTDOWN_CLOSE_CODE,
+                        reason: "Server shutting down.".into(),
+                    };
+                    self.write(WebsocketMessage::Close(Some(frame))).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_client_message(&mut self, msg: Message) -> Result<(), WebsocketError> {
+        tracing::trace!("Received: {:?}", msg);
+        let max_size = self.session.settings.max_inbound_message_size;
         match msg {
-            Err(e) => tracing::info!("Client disconnected: {:?}", e),
-            Ok(msg) => {
-                tracing::trace!("Received: {:?}", msg);
-                match msg {
-                    Message::Text(msg) => match serde_json::from_str::<ClientMessage>(&msg) {
-                        Ok(msg) => {
-                            let tx = match msg.system {
-                                WebsocketSystem::PythonRepo => &python_repo_tx,
-                                WebsocketSystem::PcUsage => &pc_usage_tx,
-                            };
-                            tx.send(msg.into()).await?;
+            Message::Text(msg) if msg.len() > max_size => {
+                tracing::info!("Rejected message of {} bytes.", msg.len());
+                let e = format!(
+                    "Message of {} bytes exceeds the maximum size of {} bytes.",
+                    msg.len(),
+                    max_size
+                );
+                let result = ResultMessage::from_error_code(ErrorCode::MessageTooLarge, e, None);
+                self.write(WebsocketMessage::TaskResult(result)).await?;
+            }
+            Message::Text(msg) => self.handle_text(&msg).await?,
+            Message::Binary(_) => {
+                tracing::info!("Invalid binary message from client.");
+            }
+            Message::Ping(msg) => {
+                self.session.beat();
+                self.write(WebsocketMessage::Ping(msg)).await?;
+            }
+            Message::Pong(msg) => self.session.record_pong(&msg),
+            Message::Close(_) => {
+                tracing::info!("Client closed the websocket.");
+                self.closing = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Dispatches the messages of a text frame to their subsystems.
+    async fn handle_text(&mut self, text: &str) -> Result<(), WebsocketError> {
+        if self.session.protocol == Protocol::JsonRpc {
+            self.session.idle.touch();
+            return self.handle_jsonrpc_text(text).await;
+        }
+        let frame = match ClientFrame::parse(text) {
+            Ok(frame) => frame,
+            Err(e) => {
+                tracing::info!("Failed to deserialize message: {:?}", e);
+                let result = ResultMessage::from_error(e, None);
+                return self.write(WebsocketMessage::TaskResult(result)).await;
+            }
+        };
+        // Application pings only keep the heartbeat alive
+        if !matches!(frame, ClientFrame::Ping(_)) {
+            self.session.idle.touch();
+        }
+        match frame {
+            ClientFrame::Hello(hello) => {
+                let msg = match self.session.negotiate(hello) {
+                    Ok(negotiated) => {
+                        tracing::info!("Negotiated session: {:?}", negotiated);
+                        self.subsystems.context.set_negotiated(negotiated.clone());
+                        let period = negotiated.heartbeat_interval;
+                        self.heartbeat = interval_at(tokio::time::Instant::now() + period, period);
+                        WebsocketMessage::Handshake(HandshakeMessage::Welcome(negotiated))
+                    }
+                    Err(e) => {
+                        tracing::info!("Handshake failed: {}", e);
+                        let result =
+                            ResultMessage::from_error_code(ErrorCode::HandshakeFailed, e, None);
+                        WebsocketMessage::TaskResult(result)
+                    }
+                };
+                self.write(msg).await?;
+            }
+            ClientFrame::Ping(ping) => {
+                self.session.beat();
+                let pong = PongMessage::new(ping.id, self.session.latency().clone());
+                self.write(WebsocketMessage::Pong(pong)).await?;
+            }
+            ClientFrame::Message(msg) => self.dispatch(msg).await?,
+            ClientFrame::Batch(messages) | ClientFrame::CombinedBatch(messages)
+                if messages.len() > self.session.settings.max_batch_size =>
+            {
+                let result = batch_too_large(messages.len(), &self.session.settings);
+                self.write(WebsocketMessage::TaskResult(result)).await?;
+            }
+            ClientFrame::Batch(messages) => {
+                for msg in messages {
+                    match msg {
+                        Ok(msg) => self.dispatch(msg).await?,
+                        Err(e) => {
+                            let result = ResultMessage::from_error(e, None);
+                            self.write(WebsocketMessage::TaskResult(result)).await?;
                         }
+                    }
+                }
+            }
+            ClientFrame::Pipeline(request)
+                if request.pipeline.len() > self.session.settings.max_batch_size =>
+            {
+                let result = batch_too_large(request.pipeline.len(), &self.session.settings)
+                    .with_id(request.id);
+                self.write(WebsocketMessage::TaskResult(result)).await?;
+            }
+            ClientFrame::Pipeline(request) => self.subsystems.pipeline(request),
+            ClientFrame::CombinedBatch(messages) => {
+                let mut replies = Vec::with_capacity(messages.len());
+                for msg in messages {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    match msg {
+                        Ok(msg) => self.dispatch_with_reply(msg, reply_tx).await?,
                         Err(e) => {
-                            tracing::info!("Failed to deserialize message: {:?}", e);
-                            sender
-                                .send(WebsocketMessage::TaskResult(ResultMessage::from_error(
-                                    e, None,
-                                )))
-                                .await?;
+                            let _ = reply_tx.send(ResultMessage::from_error(e, None));
                         }
-                    },
-                    Message::Binary(_) => {
-                        tracing::info!("Invalid binary message from client.");
                     }
-                    Message::Ping(msg) => {
-                        *session.hb.lock().unwrap() = Instant::now();
-                        sender.send(WebsocketMessage::Ping(msg)).await?;
+                    replies.push(reply_rx);
+                }
+                self.batches.push(collect_batch(replies).boxed());
+            }
+        }
+        Ok(())
+    }
+
+    /// Dispatches the requests of a JSON-RPC frame, batches are answered in a single frame.
+    async fn handle_jsonrpc_text(&mut self, text: &str) -> Result<(), WebsocketError> {
+        match jsonrpc::Frame::parse(text) {
+            jsonrpc::Frame::Single(Ok(None)) => {}
+            jsonrpc::Frame::Single(Ok(Some(call))) if call.notification => {
+                // The result is dropped along with the reply receiver
+                let (reply_tx, _) = oneshot::channel();
+                self.dispatch_with_reply(call.message, reply_tx).await?;
+            }
+            jsonrpc::Frame::Single(Ok(Some(call))) => self.dispatch(call.message).await?,
+            jsonrpc::Frame::Single(Err(result)) => {
+                self.write(WebsocketMessage::TaskResult(*result)).await?;
+            }
+            jsonrpc::Frame::Batch(calls) if calls.len() > self.session.settings.max_batch_size => {
+                let result = batch_too_large(calls.len(), &self.session.settings);
+                self.write(WebsocketMessage::TaskResult(result)).await?;
+            }
+            jsonrpc::Frame::Batch(calls) => {
+                let mut replies = Vec::with_capacity(calls.len());
+                for call in calls {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    match call {
+                        Ok(None) => continue,
+                        Ok(Some(call)) => {
+                            let notification = call.notification;
+                            self.dispatch_with_reply(call.message, reply_tx).await?;
+                            if notification {
+                                continue;
+                            }
+                        }
+                        Err(result) => {
+                            let _ = reply_tx.send(*result);
+                        }
                     }
-                    Message::Pong(_) => {
-                        *session.hb.lock().unwrap() = Instant::now();
+                    replies.push(reply_rx);
+                }
+                // A batch of notifications gets no response
+                if !replies.is_empty() {
+                    self.batches.push(collect_batch(replies).boxed());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn dispatch(&mut self, msg: ClientMessage) -> Result<(), WebsocketError> {
+        if msg.background {
+            let result = self.subsystems.submit_job(msg);
+            return self.write(WebsocketMessage::TaskResult(result)).await;
+        }
+        let system = msg.system;
+        let task = self.subsystems.task(msg);
+        self.send_task(system, task).await
+    }
+
+    /// Dispatches the message, the result is sent to `reply` instead of the session.
+    async fn dispatch_with_reply(
+        &mut self,
+        msg: ClientMessage,
+        reply: oneshot::Sender<ResultMessage>,
+    ) -> Result<(), WebsocketError> {
+        if msg.background {
+            let _ = reply.send(self.subsystems.submit_job(msg));
+            return Ok(());
+        }
+        let system = msg.system;
+        let mut task = self.subsystems.task(msg);
+        task.reply = Some(reply);
+        self.send_task(system, task).await
+    }
+
+    /// Queues the task on its subsystem worker. Results are still delivered while the queue is
+    /// full, as the worker may be waiting for room in the results channel.
+    async fn send_task(
+        &mut self,
+        system: WebsocketSystem,
+        task: TaskMessage,
+    ) -> Result<(), WebsocketError> {
+        let mut tx = self.subsystems.sender(system);
+        loop {
+            let worker_stopped = tokio::select! {
+                permit = tx.reserve() => match permit {
+                    Ok(permit) => {
+                        permit.send(task);
+                        return Ok(());
                     }
-                    Message::Close(_) => todo!(),
+                    Err(_) => true,
+                },
+                Some(msg) = self.results.recv() => {
+                    self.write(msg).await?;
+                    false
                 }
+            };
+            // The worker shut down after being unused
+            if worker_stopped {
+                tx = self.subsystems.restart(system);
             }
         }
     }
-    Ok(())
+
+    /// Serializes a message and writes it to the socket.
+    async fn write(&mut self, msg: WebsocketMessage) -> Result<(), WebsocketError> {
+        tracing::trace!("Sending: {:?}", msg);
+        let protocol = self.session.protocol;
+        let max_message_size = self.session.settings.max_outbound_message_size;
+        let msg = match msg {
+            WebsocketMessage::Ping(msg) => Message::Ping(msg),
+            WebsocketMessage::Close(frame) => {
+                self.closing = true;
+                Message::Close(frame)
+            }
+            WebsocketMessage::Handshake(msg) => Message::Text(
+                serde_json::to_string(&msg).context("Failed to serialize HandshakeMessage")?,
+            ),
+            WebsocketMessage::Pong(msg) => Message::Text(
+                serde_json::to_string(&msg).context("Failed to serialize PongMessage")?,
+            ),
+            WebsocketMessage::IdleWarning(msg) => Message::Text(
+                match protocol {
+                    Protocol::Native | Protocol::GraphQL => serde_json::to_string(&msg),
+                    Protocol::JsonRpc => jsonrpc::encode_notification(
+                        "idle_warning",
+                        serde_json::json!({ "closes_in": msg.closes_in.as_millis() as u64 }),
+                    ),
+                }
+                .context("Failed to serialize IdleWarning")?,
+            ),
+            WebsocketMessage::ScheduleResult(msg) => Message::Text(
+                match protocol {
+                    Protocol::Native | Protocol::GraphQL => serde_json::to_string(&msg),
+                    Protocol::JsonRpc => serde_json::to_value(&msg)
+                        .and_then(|params| jsonrpc::encode_notification("schedule_result", params)),
+                }
+                .context("Failed to serialize ScheduleResult")?,
+            ),
+            WebsocketMessage::TaskResult(msg) => Message::Text(
+                match protocol {
+                    Protocol::Native | Protocol::GraphQL => msg.to_json_limited(max_message_size),
+                    Protocol::JsonRpc => jsonrpc_limited(msg, max_message_size),
+                }
+                .context("Failed to serialize ClientMessage")?,
+            ),
+            WebsocketMessage::BatchResult(msg) => Message::Text(
+                match protocol {
+                    Protocol::Native | Protocol::GraphQL => msg.to_json_limited(max_message_size),
+                    Protocol::JsonRpc => {
+                        let budget = max_message_size / msg.batch.len().max(1);
+                        msg.batch
+                            .into_iter()
+                            .map(|msg| jsonrpc_limited(msg, budget))
+                            .collect::<Result<Vec<_>, _>>()
+                            .map(|responses| format!("[{}]", responses.join(",")))
+                    }
+                }
+                .context("Failed to serialize BatchResultMessage")?,
+            ),
+        };
+        self.socket
+            .send(msg)
+            .await
+            .context("Failed to send message to socket.")?;
+        Ok(())
+    }
 }
 
-#[tracing::instrument(
-    name = "Internal receiver task",
-    level = "trace"
-    skip(rx, socket_sender),
-)]
-async fn receive_message(
-    mut rx: mpsc::Receiver<WebsocketMessage>,
-    mut socket_sender: SplitSink<WebSocket, Message>,
-) -> Result<(), WebsocketError> {
-    while let Some(msg) = rx.recv().await {
-        tracing::trace!("Received: {:?}", msg);
-        match msg {
-            WebsocketMessage::Ping(msg) => {
-                socket_sender
-                    .send(Message::Ping(msg))
-                    .await
-                    .context("Failed to send Ping message to socket.")?;
-            }
-            WebsocketMessage::Close => {
-                socket_sender
-                    .send(Message::Close(None))
-                    .await
-                    .context("Failed to send Close message to socket.")?;
-                break;
-            }
-            WebsocketMessage::TaskResult(msg) => {
-                let msg =
-                    serde_json::to_string(&msg).context("Failed to serialize ClientMessage")?;
-                socket_sender
-                    .send(Message::Text(msg))
-                    .await
-                    .context("Failed to send ClientMessage to socket.")?;
-            }
-        }
-    }
-    Ok(())
+/// A running subsystem task of a session.
+struct Worker {
+    tx: mpsc::Sender<TaskMessage>,
+    handle: JoinHandle<Result<(), WebsocketError>>,
+}
+
+/// Channels to the subsystem tasks of a session.
+///
+/// Tasks are started on the first message addressed to them and stop by themselves after
+/// `worker_idle_timeout` without messages.
+struct SubsystemSenders {
+    registry: SubsystemRegistry,
+    states: SessionStates,
+    context: SessionContext,
+    workers: HashMap<WebsocketSystem, Worker>,
+    /// Replaced workers still finishing the tasks queued before they shut down
+    retired: Vec<JoinHandle<Result<(), WebsocketError>>>,
+    /// Running pipelines
+    pipelines: Vec<JoinHandle<()>>,
+    sender: mpsc::Sender<WebsocketMessage>,
+    settings: Arc<WebsocketSettings>,
+    idle: IdleTracker,
+}
+
+impl SubsystemSenders {
+    fn new(
+        registry: SubsystemRegistry,
+        states: SessionStates,
+        context: SessionContext,
+        sender: mpsc::Sender<WebsocketMessage>,
+        settings: Arc<WebsocketSettings>,
+        idle: IdleTracker,
+    ) -> Self {
+        Self {
+            registry,
+            states,
+            context,
+            workers: HashMap::new(),
+            retired: Vec::new(),
+            pipelines: Vec::new(),
+            sender,
+            settings,
+            idle,
+        }
+    }
+
+    /// Channel to the worker of `system`, starting it if it is not running.
+    fn sender(&mut self, system: WebsocketSystem) -> mpsc::Sender<TaskMessage> {
+        match self.workers.get(&system) {
+            Some(worker) if !worker.tx.is_closed() => worker.tx.clone(),
+            _ => self.restart(system),
+        }
+    }
+
+    fn restart(&mut self, system: WebsocketSystem) -> mpsc::Sender<TaskMessage> {
+        tracing::debug!("Starting {:?} worker.", system);
+        let (tx, rx) = mpsc::channel(32);
+        let sender = self.sender.clone();
+        let settings = self.settings.clone();
+        let context = self.context.clone();
+        let service = self.registry.service(system, &self.states);
+        let handle = tokio_spawn(service::handle_messages(
+            system, service, rx, sender, settings, context,
+        ));
+        let worker = Worker {
+            tx: tx.clone(),
+            handle,
+        };
+        // A replaced worker finishes its queued tasks on its own, unless the session ends first
+        self.retired.retain(|handle| !handle.is_finished());
+        if let Some(replaced) = self.workers.insert(system, worker) {
+            if !replaced.handle.is_finished() {
+                self.retired.push(replaced.handle);
+            }
+        }
+        tx
+    }
+
+    /// Submits the message as a background job, returning the reply with its ID.
+    ///
+    /// Jobs get fresh subsystem state and do not keep the session active, as they outlive it.
+    fn submit_job(&self, msg: ClientMessage) -> ResultMessage {
+        let system = msg.system;
+        let id = msg.id.clone();
+        let jobs = self.registry.jobs.manager();
+        let timeout = jobs.settings().timeout;
+        let timeout = msg.timeout.map_or(timeout, |t| t.min(timeout));
+        let request = TaskRequest {
+            system,
+            task: msg.task,
+            payload: msg.payload,
+            id: msg.id,
+            stream: false,
+            no_cache: msg.no_cache,
+            idempotency_key: msg.idempotency_key,
+            ctx: TaskContext {
+                progress: ProgressReporter::disabled(system),
+                deadline: tokio::time::Instant::now() + timeout,
+                session: Some(self.context.clone()),
+            },
+            session: self.context.info.clone(),
+        };
+        let service = self.registry.service(system, &SessionStates::default());
+        let result = match jobs.submit(service, request) {
+            Ok(job_id) => ResultMessage::from_json(
+                serde_json::json!({ "job_id": job_id.to_string() }),
+                Some(system),
+            ),
+            Err(e) => ResultMessage::from_error(e, Some(system)),
+        };
+        result.with_id(id)
+    }
+
+    /// Runs the pipeline in its own task, which sends the combined result to the session.
+    fn pipeline(&mut self, request: PipelineRequest) {
+        let registry = self.registry.clone();
+        let states = self.states.clone();
+        let settings = self.settings.clone();
+        let context = self.context.clone();
+        let sender = self.sender.clone();
+        let in_flight = self.idle.track();
+        self.pipelines.retain(|pipeline| !pipeline.is_finished());
+        self.pipelines.push(tokio_spawn(async move {
+            let service = |system| registry.service(system, &states);
+            let result = run_pipeline(request, service, &settings, context).await;
+            drop(in_flight);
+            let _ = sender.send(WebsocketMessage::TaskResult(result)).await;
+        }));
+    }
+
+    /// The session is not idle while the task is running.
+    fn task(&self, msg: ClientMessage) -> TaskMessage {
+        let mut task = TaskMessage::from(msg);
+        task.in_flight = Some(self.idle.track());
+        task
+    }
+}
+
+impl Drop for SubsystemSenders {
+    /// Running tasks are aborted when the session ends.
+    fn drop(&mut self) {
+        for worker in self.workers.values() {
+            worker.handle.abort();
+        }
+        for worker in &self.retired {
+            worker.abort();
+        }
+        for pipeline in &self.pipelines {
+            pipeline.abort();
+        }
+    }
+}
+
+fn batch_too_large(len: usize, settings: &WebsocketSettings) -> ResultMessage {
+    let e = format!(
+        "Batch of {} messages exceeds the maximum size of {} messages.",
+        len, settings.max_batch_size
+    );
+    ResultMessage::from_error_code(ErrorCode::BatchTooLarge, e, None)
+}
+
+/// Waits for every result of a combined batch.
+async fn collect_batch(replies: Vec<oneshot::Receiver<ResultMessage>>) -> BatchResultMessage {
+    let batch = futures::future::join_all(replies)
+        .await
+        .into_iter()
+        .map(|reply| {
+            reply.unwrap_or_else(|_| ResultMessage::from_error("Task was cancelled.", None))
+        })
+        .collect();
+    BatchResultMessage { batch }
+}
+
+/// Encodes a JSON-RPC response, oversized results are replaced by an error as JSON-RPC
+/// has no way to flag a truncated result.
+fn jsonrpc_limited(msg: ResultMessage, max_size: usize) -> Result<String, serde_json::Error> {
+    let id = msg.id.clone();
+    let json = jsonrpc::encode(msg)?;
+    if json.len() <= max_size {
+        return Ok(json);
+    }
+    let e = format!(
+        "Result exceeds the maximum message size of {} bytes.",
+        max_size
+    );
+    jsonrpc::encode(ResultMessage::from_error_code(ErrorCode::ResultTooLarge, e, None).with_id(id))
 }
@@ -0,0 +1,93 @@
+use crate::helpers::{next_json, next_result, send_text, spawn_app, spawn_app_with};
+use axum_websockets::message::{
+    BatchResultMessage, ErrorCode, ErrorPayload, RequestId, ResultMessage,
+};
+
+#[actix_rt::test]
+async fn batch_receives_individual_results() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = serde_json::json!([
+        {"id": 1, "system": "pc_usage", "task": "cpu_load"},
+        {"id": 2, "system": "python_repo", "task": "get_files", "payload": "tests/examples"},
+        {"id": 3, "system": "python_repo", "task": "get_files", "payload": "tests/examples/pkg"},
+    ])
+    .to_string();
+    let mut connection = app.connect().await;
+
+    // Act
+    send_text(&mut connection, &message).await;
+    let mut results = Vec::new();
+    for _ in 0..3 {
+        results.push(next_result(&mut connection).await);
+    }
+
+    // Assert
+    let mut ids = results
+        .iter()
+        .map(|r| {
+            assert!(r.success, "Call was not successful.");
+            r.id.clone()
+        })
+        .collect::<Vec<_>>();
+    ids.sort_by_key(|id| format!("{:?}", id));
+    assert_eq!(
+        ids,
+        vec![
+            Some(RequestId::Number(1)),
+            Some(RequestId::Number(2)),
+            Some(RequestId::Number(3))
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn combined_batch_receives_results_in_request_order() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = serde_json::json!({
+        "combine": true,
+        "batch": [
+            {"id": "cpu", "system": "pc_usage", "task": "cpu_load"},
+            {"id": "files", "system": "python_repo", "task": "get_files", "payload": "tests/examples"},
+            {"id": "invalid", "system": "invalid_system", "task": "get_files"},
+        ]
+    })
+    .to_string();
+    let mut connection = app.connect().await;
+
+    // Act
+    send_text(&mut connection, &message).await;
+    let msg = next_json(&mut connection).await;
+
+    // Assert
+    let batch = serde_json::from_value::<BatchResultMessage>(msg)
+        .expect("Failed to deserialize batch result.")
+        .batch;
+    assert_eq!(batch.len(), 3);
+    assert_eq!(batch[0].id, Some(RequestId::String("cpu".into())));
+    assert!(batch[0].success, "Call was not successful.");
+    assert_eq!(batch[1].id, Some(RequestId::String("files".into())));
+    assert!(batch[1].success, "Call was not successful.");
+    assert!(!batch[2].success, "Call should not success.");
+}
+
+#[actix_rt::test]
+async fn batch_over_the_limit_receives_error() {
+    // Arrange
+    let app = spawn_app_with(|c| c.websocket.max_batch_size = 1).await;
+    let message = serde_json::json!([
+        {"system": "pc_usage", "task": "cpu_load"},
+        {"system": "pc_usage", "task": "cpu_load"},
+    ])
+    .to_string();
+
+    // Act
+    let result: ResultMessage = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(!result.success, "Call should not success.");
+    let error = serde_json::from_value::<ErrorPayload>(result.payload)
+        .expect("Failed to deserialize error payload.");
+    assert_eq!(error.code, ErrorCode::BatchTooLarge);
+}
@@ -0,0 +1,158 @@
+use crate::helpers::{next_result, send_text, spawn_app_with, spawn_app_with_layers, TestApp};
+use awc::Client;
+use axum_websockets::{
+    configuration::Settings,
+    message::{ErrorCode, ErrorPayload},
+    service::{identity::RequireIdentityLayer, TaskLayers},
+    subsystems::WebsocketSystem,
+};
+use std::{collections::HashMap, time::Duration};
+use tempfile::TempDir;
+
+/// Temporary directory with `count` python files, removed when dropped.
+fn python_dir(count: usize) -> TempDir {
+    let dir = TempDir::new().expect("Failed to create directory.");
+    for i in 0..count {
+        add_file(dir.path(), i);
+    }
+    dir
+}
+
+fn add_file(dir: &std::path::Path, i: usize) {
+    std::fs::write(dir.join(format!("file_{}.py", i)), "").expect("Failed to write file.");
+}
+
+fn cache_get_files(c: &mut Settings, ttl: Duration) {
+    let tasks = HashMap::from([("get_files".to_string(), ttl)]);
+    c.cache.ttl.insert(WebsocketSystem::PythonRepo, tasks);
+}
+
+async fn file_count(app: &TestApp, dir: &std::path::Path, no_cache: bool) -> usize {
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": dir,
+        "no_cache": no_cache,
+    })
+    .to_string();
+    let result = app.get_first_result(&message).await;
+    assert!(result.success, "Failed: {:?}", result.payload);
+    result.payload.as_array().map(Vec::len).unwrap_or_default()
+}
+
+#[actix_rt::test]
+async fn cached_results_are_shared_between_sessions() {
+    // Arrange
+    let app = spawn_app_with(|c| cache_get_files(c, Duration::from_secs(60))).await;
+    let dir = python_dir(2);
+    file_count(&app, dir.path(), false).await;
+    add_file(dir.path(), 2);
+
+    // Act
+    let count = file_count(&app, dir.path(), false).await;
+
+    // Assert
+    assert_eq!(count, 2, "Result was not cached.");
+}
+
+#[actix_rt::test]
+async fn no_cache_flag_bypasses_and_refreshes_the_cache() {
+    // Arrange
+    let app = spawn_app_with(|c| cache_get_files(c, Duration::from_secs(60))).await;
+    let dir = python_dir(2);
+    file_count(&app, dir.path(), false).await;
+    add_file(dir.path(), 2);
+
+    // Act
+    let bypassed = file_count(&app, dir.path(), true).await;
+    let refreshed = file_count(&app, dir.path(), false).await;
+
+    // Assert
+    assert_eq!(bypassed, 3);
+    assert_eq!(refreshed, 3);
+}
+
+#[actix_rt::test]
+async fn cached_results_expire() {
+    // Arrange
+    let app = spawn_app_with(|c| cache_get_files(c, Duration::from_millis(50))).await;
+    let dir = python_dir(2);
+    file_count(&app, dir.path(), false).await;
+    add_file(dir.path(), 2);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Act
+    let count = file_count(&app, dir.path(), false).await;
+
+    // Assert
+    assert_eq!(count, 3);
+}
+
+#[actix_rt::test]
+async fn tasks_without_ttl_are_not_cached() {
+    // Arrange
+    let app = spawn_app_with(|_| {}).await;
+    let dir = python_dir(2);
+    file_count(&app, dir.path(), false).await;
+    add_file(dir.path(), 2);
+
+    // Act
+    let count = file_count(&app, dir.path(), false).await;
+
+    // Assert
+    assert_eq!(count, 3);
+}
+
+#[actix_rt::test]
+async fn oldest_results_are_evicted_when_full() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        cache_get_files(c, Duration::from_secs(60));
+        c.cache.max_entries = 1;
+    })
+    .await;
+    let (first, second) = (python_dir(1), python_dir(1));
+    file_count(&app, first.path(), false).await;
+    file_count(&app, second.path(), false).await;
+    add_file(first.path(), 1);
+    add_file(second.path(), 1);
+
+    // Act
+    let cached = file_count(&app, second.path(), false).await;
+    let evicted = file_count(&app, first.path(), false).await;
+
+    // Assert
+    assert_eq!(evicted, 2, "Oldest result was not evicted.");
+    assert_eq!(cached, 1, "Newest result was evicted.");
+}
+
+#[actix_rt::test]
+async fn cached_results_are_not_served_past_the_task_layers() {
+    // Arrange
+    let layers = TaskLayers::default().layer(RequireIdentityLayer);
+    let app = spawn_app_with_layers(|c| cache_get_files(c, Duration::from_secs(60)), layers).await;
+    let dir = python_dir(2);
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": dir.path(),
+    })
+    .to_string();
+    let (_response, mut alice) = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .header("x-forwarded-user", "alice")
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+    send_text(&mut alice, &message).await;
+    let cached = next_result(&mut alice).await;
+    assert!(cached.success, "Failed: {:?}", cached.payload);
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(!result.success, "Anonymous session got the cached result.");
+    let error = serde_json::from_value::<ErrorPayload>(result.payload).expect("Not an error.");
+    assert_eq!(error.code, ErrorCode::Unauthorized);
+}
@@ -0,0 +1,126 @@
+use crate::helpers::spawn_app_with;
+use awc::{error::WsClientError, http::StatusCode, Client};
+use std::time::Duration;
+
+#[actix_rt::test]
+async fn upgrade_is_rejected_over_the_per_ip_limit() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.connection_limits.max_sessions_per_ip = 1;
+        // The test client doesn't answer pings, keep the first session from timing out and
+        // freeing its slot while we connect again
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let _first = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+
+    // Act
+    let second = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .connect()
+        .await;
+
+    // Assert
+    match second {
+        Err(WsClientError::InvalidResponseStatus(status)) => {
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS)
+        }
+        other => panic!("Expected a rejected upgrade, got: {:?}", other.map(|_| ())),
+    }
+}
+
+#[actix_rt::test]
+async fn upgrade_is_rejected_over_the_global_limit() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.connection_limits.max_sessions = 1;
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let _first = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+
+    // Act
+    let second = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .connect()
+        .await;
+
+    // Assert
+    match second {
+        Err(WsClientError::InvalidResponseStatus(status)) => {
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE)
+        }
+        other => panic!("Expected a rejected upgrade, got: {:?}", other.map(|_| ())),
+    }
+}
+
+#[actix_rt::test]
+async fn upgrade_is_rejected_over_the_per_identity_limit() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.connection_limits.max_sessions_per_identity = 1;
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let _first = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .header("x-forwarded-user", "alice")
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+    let _other_identity = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .header("x-forwarded-user", "bob")
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+
+    // Act
+    let second = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .header("x-forwarded-user", "alice")
+        .connect()
+        .await;
+
+    // Assert
+    match second {
+        Err(WsClientError::InvalidResponseStatus(status)) => {
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS)
+        }
+        other => panic!("Expected a rejected upgrade, got: {:?}", other.map(|_| ())),
+    }
+}
+
+#[actix_rt::test]
+async fn health_check_reports_connection_usage() {
+    // Arrange
+    let app = spawn_app_with(|c| c.websocket.client_timeout = Duration::from_secs(10)).await;
+    let _connection = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+
+    // Act
+    let health = Client::new()
+        .get(format!("{}/health", app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<serde_json::Value>()
+        .await
+        .expect("Failed to parse health report.");
+
+    // Assert
+    assert_eq!(health["status"], "ok");
+    assert_eq!(health["connections"]["sessions"], 1);
+    assert_eq!(health["connections"]["peer_ips"], 1);
+}
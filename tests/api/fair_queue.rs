@@ -0,0 +1,152 @@
+use crate::helpers::{next_result, send_text, spawn_app_with, Connection, TestApp};
+use awc::Client;
+use axum_websockets::{
+    configuration::{get_configuration, Priority, Settings, TaskCost},
+    subsystems::WebsocketSystem,
+    Application,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+const CPU_LOAD: &str = r#"{"system": "pc_usage", "task": "cpu_load", "no_cache": true}"#;
+const CPU_LOAD_JOB: &str =
+    r#"{"system": "pc_usage", "task": "cpu_load", "no_cache": true, "background": true}"#;
+const GET_FILES: &str = r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples", "no_cache": true}"#;
+
+/// Lets a single `heavy` task run at once, `tasks` being in that class.
+fn one_heavy_task(
+    tasks: &'static [(WebsocketSystem, &'static str, Priority)],
+) -> impl FnOnce(&mut Settings) {
+    move |c| {
+        c.fair_queue.classes = HashMap::from([("heavy".to_string(), 1)]);
+        c.fair_queue.tasks.clear();
+        for (system, task, priority) in tasks {
+            c.fair_queue.tasks.entry(*system).or_default().insert(
+                task.to_string(),
+                TaskCost {
+                    class: "heavy".to_string(),
+                    priority: *priority,
+                },
+            );
+        }
+        c.subsystems.pc_usage.sample_duration = Duration::from_millis(200);
+        c.subsystems.pc_usage.sample_ttl = Duration::ZERO;
+        c.websocket.client_timeout = Duration::from_secs(10);
+    }
+}
+
+async fn connect_as(app: &TestApp, user: &str) -> Connection {
+    let (_, connection) = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .header("x-forwarded-user", user)
+        .connect()
+        .await
+        .expect("Failed to connect.");
+    connection
+}
+
+/// Statuses of the jobs visible to the connection, oldest first.
+async fn job_statuses(connection: &mut Connection) -> Vec<String> {
+    send_text(connection, r#"{"system": "jobs", "task": "list"}"#).await;
+    let result = next_result(connection).await;
+    result
+        .payload
+        .as_array()
+        .expect("Not a job list.")
+        .iter()
+        .map(|job| job["status"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[actix_rt::test]
+async fn class_limits_concurrent_tasks() {
+    // Arrange
+    let tasks = &[(WebsocketSystem::PcUsage, "cpu_load", Priority::Normal)];
+    let app = spawn_app_with(one_heavy_task(tasks)).await;
+    let mut first = app.connect().await;
+    let mut second = app.connect().await;
+    let started = Instant::now();
+
+    // Act
+    send_text(&mut first, CPU_LOAD).await;
+    send_text(&mut second, CPU_LOAD).await;
+    let first = next_result(&mut first).await;
+    let second = next_result(&mut second).await;
+
+    // Assert
+    assert!(first.success && second.success, "Tasks failed.");
+    assert!(
+        started.elapsed() >= Duration::from_millis(400),
+        "Tasks ran concurrently."
+    );
+}
+
+#[actix_rt::test]
+async fn users_take_turns() {
+    // Arrange
+    let tasks = &[(WebsocketSystem::PcUsage, "cpu_load", Priority::Normal)];
+    let app = spawn_app_with(one_heavy_task(tasks)).await;
+    let mut alice = connect_as(&app, "alice").await;
+    for _ in 0..3 {
+        send_text(&mut alice, CPU_LOAD_JOB).await;
+        next_result(&mut alice).await;
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let mut bob = connect_as(&app, "bob").await;
+
+    // Act
+    send_text(&mut bob, CPU_LOAD).await;
+    let result = next_result(&mut bob).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    let statuses = job_statuses(&mut alice).await;
+    assert!(
+        statuses.iter().any(|status| status == "running"),
+        "Bob waited for all of Alice's jobs: {:?}",
+        statuses
+    );
+}
+
+#[actix_rt::test]
+async fn higher_priority_tasks_run_first() {
+    // Arrange
+    let tasks = &[
+        (WebsocketSystem::PcUsage, "cpu_load", Priority::Low),
+        (WebsocketSystem::PythonRepo, "get_files", Priority::High),
+    ];
+    let app = spawn_app_with(one_heavy_task(tasks)).await;
+    let mut alice = connect_as(&app, "alice").await;
+    for _ in 0..2 {
+        send_text(&mut alice, CPU_LOAD_JOB).await;
+        next_result(&mut alice).await;
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let mut bob = connect_as(&app, "bob").await;
+
+    // Act
+    send_text(&mut bob, GET_FILES).await;
+    let result = next_result(&mut bob).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    let statuses = job_statuses(&mut alice).await;
+    assert_eq!(statuses, ["succeeded", "running"]);
+}
+
+#[actix_rt::test]
+async fn undefined_cost_classes_are_rejected_on_startup() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.port = 0;
+    configuration.subsystems.jobs.store = None;
+    configuration.fair_queue.classes.clear();
+
+    // Act
+    let application = Application::build(configuration);
+
+    // Assert
+    assert!(application.is_err(), "Built with an undefined cost class.");
+}
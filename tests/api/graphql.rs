@@ -0,0 +1,126 @@
+use crate::helpers::{next_json, send_text, spawn_app, spawn_app_with_layers, Connection, TestApp};
+use awc::Client;
+use axum_websockets::service::{identity::RequireIdentityLayer, TaskLayers};
+
+async fn connect_graphql(app: &TestApp) -> Connection {
+    let (_response, mut connection) = Client::new()
+        .ws(format!("{}/graphql", app.address))
+        .protocols(["graphql-transport-ws"])
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+    send_text(&mut connection, r#"{"type": "connection_init"}"#).await;
+    let ack = next_json(&mut connection).await;
+    assert_eq!(ack["type"], "connection_ack");
+    connection
+}
+
+async fn subscribe(connection: &mut Connection, id: &str, query: &str) {
+    let message = serde_json::json!({
+        "id": id,
+        "type": "subscribe",
+        "payload": {"query": query}
+    });
+    send_text(connection, &message.to_string()).await;
+}
+
+#[actix_rt::test]
+async fn graphql_query_returns_task_result_and_completes() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = connect_graphql(&app).await;
+
+    // Act
+    subscribe(
+        &mut connection,
+        "1",
+        r#"{ pythonRepo { getFiles(path: "tests/examples") } }"#,
+    )
+    .await;
+    let next = next_json(&mut connection).await;
+    let complete = next_json(&mut connection).await;
+
+    // Assert
+    assert_eq!(next["type"], "next");
+    assert_eq!(next["id"], "1");
+    let files = &next["payload"]["data"]["pythonRepo"]["getFiles"];
+    assert_eq!(files.as_array().map(Vec::len), Some(4));
+    assert_eq!(complete["type"], "complete");
+    assert_eq!(complete["id"], "1");
+}
+
+#[actix_rt::test]
+async fn graphql_subscription_receives_periodic_cpu_load() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = connect_graphql(&app).await;
+
+    // Act
+    subscribe(
+        &mut connection,
+        "cpu",
+        "subscription { cpuLoad(intervalMs: 50) }",
+    )
+    .await;
+    let first = next_json(&mut connection).await;
+    let second = next_json(&mut connection).await;
+
+    // Assert
+    for msg in [first, second] {
+        assert_eq!(msg["type"], "next");
+        assert_eq!(msg["id"], "cpu");
+        assert!(
+            !msg["payload"]["data"]["cpuLoad"].is_null(),
+            "Unexpected payload: {:?}",
+            msg
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn graphql_queries_go_through_the_task_layers() {
+    // Arrange
+    let layers = TaskLayers::default().layer(RequireIdentityLayer);
+    let app = spawn_app_with_layers(|_| {}, layers).await;
+    let mut connection = connect_graphql(&app).await;
+
+    // Act
+    subscribe(
+        &mut connection,
+        "1",
+        r#"{ pythonRepo { getFiles(path: "tests/examples") } }"#,
+    )
+    .await;
+    let next = next_json(&mut connection).await;
+
+    // Assert
+    assert_eq!(next["type"], "next");
+    assert!(
+        next["payload"]["data"]["pythonRepo"].is_null(),
+        "Unexpected: {:?}",
+        next
+    );
+    let error = &next["payload"]["errors"][0];
+    assert_eq!(error["extensions"]["code"], "unauthorized");
+}
+
+#[actix_rt::test]
+async fn graphql_upgrade_without_subprotocol_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = Client::new()
+        .ws(format!("{}/graphql", app.address))
+        .connect()
+        .await;
+
+    // Assert
+    match response {
+        Err(awc::error::WsClientError::InvalidResponseStatus(status)) => {
+            assert_eq!(status.as_u16(), 400)
+        }
+        Err(e) => panic!("Unexpected error: {:?}", e),
+        Ok(_) => panic!("Upgrade should have been rejected."),
+    }
+}
@@ -0,0 +1,150 @@
+use crate::helpers::{next_frame_json, next_json, send_text, spawn_app, spawn_app_with};
+use axum_websockets::{
+    handshake::{HandshakeMessage, Negotiated},
+    message::{ErrorCode, ErrorPayload, ResultMessage},
+    subsystems::WebsocketSystem,
+};
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+
+#[actix_rt::test]
+async fn server_sends_hello_after_upgrade() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let mut connection = app.connect().await;
+    let msg = next_frame_json(&mut connection).await;
+
+    // Assert
+    let hello = match serde_json::from_value::<HandshakeMessage>(msg) {
+        Ok(HandshakeMessage::Hello(hello)) => hello,
+        other => panic!("Expected a hello message: {:?}", other),
+    };
+    assert_eq!(hello.server.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(hello.protocol_versions, vec![1]);
+    assert_eq!(hello.systems.len(), WebsocketSystem::ALL.len());
+    assert_eq!(hello.heartbeat.interval, Duration::from_millis(50));
+}
+
+#[actix_rt::test]
+async fn client_hello_negotiates_session_within_bounds() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.min_heartbeat_interval = Duration::from_millis(20);
+        c.websocket.max_heartbeat_interval = Duration::from_millis(100);
+    })
+    .await;
+    let mut connection = app.connect().await;
+
+    // Act
+    let hello = serde_json::json!({
+        "type": "hello",
+        "protocol_versions": [2, 1],
+        "encodings": ["msgpack", "json"],
+        "heartbeat_interval": 1000
+    });
+    send_text(&mut connection, &hello.to_string()).await;
+    let msg = next_json(&mut connection).await;
+
+    // Assert
+    let negotiated = match serde_json::from_value::<HandshakeMessage>(msg) {
+        Ok(HandshakeMessage::Welcome(negotiated)) => negotiated,
+        other => panic!("Expected a welcome message: {:?}", other),
+    };
+    assert_eq!(
+        negotiated,
+        Negotiated {
+            protocol_version: 1,
+            encoding: "json".into(),
+            compression: "none".into(),
+            heartbeat_interval: Duration::from_millis(100),
+            // Keeps the configured 50ms/250ms ratio
+            client_timeout: Duration::from_millis(500),
+        }
+    );
+}
+
+#[actix_rt::test]
+async fn unsupported_client_hello_fails_handshake() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (r#"{"type": "hello", "protocol_versions": [7]}"#, "version"),
+        (r#"{"type": "hello", "encodings": ["cbor"]}"#, "encoding"),
+        (
+            r#"{"type": "hello", "compression": ["zstd"]}"#,
+            "compression",
+        ),
+    ];
+
+    for (hello, description) in test_cases {
+        let mut connection = app.connect().await;
+
+        // Act
+        send_text(&mut connection, hello).await;
+        let result = serde_json::from_value::<ResultMessage>(next_json(&mut connection).await)
+            .expect("Failed to parse result.");
+
+        // Assert
+        assert!(!result.success, "Unsupported {} was accepted.", description);
+        let error = serde_json::from_value::<ErrorPayload>(result.payload)
+            .expect("Failed to parse error payload.");
+        assert_eq!(error.code, ErrorCode::HandshakeFailed);
+    }
+}
+
+#[actix_rt::test]
+async fn second_client_hello_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect().await;
+    send_text(&mut connection, r#"{"type": "hello"}"#).await;
+    next_json(&mut connection).await;
+
+    // Act
+    send_text(&mut connection, r#"{"type": "hello"}"#).await;
+    let result = serde_json::from_value::<ResultMessage>(next_json(&mut connection).await)
+        .expect("Failed to parse result.");
+
+    // Assert
+    assert!(!result.success);
+}
+
+#[actix_rt::test]
+async fn negotiated_heartbeat_interval_is_used() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.heartbeat_interval = Duration::from_millis(1000);
+        c.websocket.client_timeout = Duration::from_millis(5000);
+        c.websocket.min_heartbeat_interval = Duration::from_millis(20);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    send_text(
+        &mut connection,
+        r#"{"type": "hello", "heartbeat_interval": 20}"#,
+    )
+    .await;
+    next_json(&mut connection).await;
+
+    // Act
+    let mut pings = 0;
+    let sleep = tokio::time::sleep(Duration::from_millis(500));
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            Some(Ok(awc::ws::Frame::Ping(msg))) = connection.next() => {
+                pings += 1;
+                connection
+                    .send(awc::ws::Message::Pong(msg))
+                    .await
+                    .expect("Failed to send Pong message.");
+            }
+            _ = &mut sleep => break,
+        }
+    }
+
+    // Assert
+    assert!(pings > 5, "Only received {} pings.", pings);
+}
@@ -1,7 +1,8 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{next_json, send_text, spawn_app};
 use awc::Client;
+use axum_websockets::{heartbeat::PongMessage, message::RequestId};
 use futures::{SinkExt, StreamExt};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[actix_rt::test]
 async fn client_receives_heartbeat_every_x_milliseconds() {
@@ -117,3 +118,78 @@ async fn client_stays_alive_if_responds_pings() {
     // Assert
     assert!(!disconnected, "Server disconnected.")
 }
+
+#[actix_rt::test]
+async fn application_ping_receives_pong_with_server_time() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect().await;
+
+    // Act
+    send_text(&mut connection, r#"{"type": "ping", "id": 7}"#).await;
+    let msg = next_json(&mut connection).await;
+
+    // Assert
+    let pong = serde_json::from_value::<PongMessage>(msg).expect("Failed to parse pong.");
+    assert_eq!(pong.id, Some(RequestId::Number(7)));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    assert!(now.abs_diff(pong.server_time) < 5_000);
+}
+
+#[actix_rt::test]
+async fn client_stays_alive_with_application_pings() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect().await;
+    let sleep = tokio::time::sleep(Duration::from_millis(500));
+    tokio::pin!(sleep);
+    let mut interval = tokio::time::interval(Duration::from_millis(50));
+    let mut disconnected = false;
+
+    // Act
+    loop {
+        tokio::select! {
+            _ = interval.tick() => send_text(&mut connection, r#"{"type": "ping"}"#).await,
+            msg = connection.next() => {
+                if let None | Some(Ok(awc::ws::Frame::Close(_))) = msg {
+                    disconnected = true;
+                    break;
+                }
+            }
+            _ = &mut sleep => break,
+        }
+    }
+
+    // Assert
+    assert!(!disconnected, "Server disconnected.")
+}
+
+#[actix_rt::test]
+async fn pong_reports_round_trip_time_of_answered_pings() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect().await;
+    let mut answered = 0;
+    while answered < 3 {
+        if let Some(Ok(awc::ws::Frame::Ping(msg))) = connection.next().await {
+            connection
+                .send(awc::ws::Message::Pong(msg))
+                .await
+                .expect("Failed to send Pong message.");
+            answered += 1;
+        }
+    }
+
+    // Act
+    send_text(&mut connection, r#"{"type": "ping"}"#).await;
+    let msg = next_json(&mut connection).await;
+
+    // Assert
+    let pong = serde_json::from_value::<PongMessage>(msg).expect("Failed to parse pong.");
+    assert!(pong.latency.samples >= 3, "{:?}", pong.latency);
+    let (min, max) = (pong.latency.min.unwrap(), pong.latency.max.unwrap());
+    assert!(min <= pong.latency.mean.unwrap() && pong.latency.mean.unwrap() <= max);
+}
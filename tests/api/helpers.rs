@@ -1,13 +1,16 @@
 use awc::Client;
 use axum_websockets::{
-    configuration::get_configuration,
+    configuration::{get_configuration, Settings},
     message::ResultMessage,
+    service::TaskLayers,
+    shutdown::Shutdown,
     telemetry::{get_subscriber, init_subscriber},
     Application,
 };
 use futures::{SinkExt, StreamExt};
 use once_cell::sync::Lazy;
 use std::time::Duration;
+use tokio::task::JoinHandle;
 
 // Ensure that 'tracing' stack is only initialized once using `once_cell`
 static TRACING: Lazy<()> = Lazy::new(|| {
@@ -31,41 +34,113 @@ static TRACING: Lazy<()> = Lazy::new(|| {
 
 pub struct TestApp {
     pub address: String,
+    #[allow(dead_code)]
     pub port: u16,
+    #[allow(dead_code)]
+    pub shutdown: Shutdown,
+    #[allow(dead_code)]
+    pub server: JoinHandle<Result<(), hyper::Error>>,
 }
 
+pub type Connection = actix_codec::Framed<awc::BoxedSocket, awc::ws::Codec>;
+
 impl TestApp {
-    pub async fn get_first_result(&self, message: &str) -> ResultMessage {
-        let (_response, mut connection) = Client::new()
-            .ws(format!("{}/ws", self.address))
+    pub async fn connect(&self) -> Connection {
+        self.connect_to("/ws").await
+    }
+
+    pub async fn connect_to(&self, path: &str) -> Connection {
+        let (_response, connection) = Client::new()
+            .ws(format!("{}{}", self.address, path))
             .connect()
             .await
             .expect("Failed to connect to websocket.");
-
         connection
-            .send(awc::ws::Message::Text(message.into()))
-            .await
-            .expect("Failed to send message.");
+    }
 
+    pub async fn get_first_result(&self, message: &str) -> ResultMessage {
+        let mut connection = self.connect().await;
+        send_text(&mut connection, message).await;
+        next_result(&mut connection).await
+    }
+
+    /// Sends `message` and collects results until `is_last` returns true.
+    pub async fn get_results(
+        &self,
+        message: &str,
+        is_last: impl Fn(&ResultMessage) -> bool,
+    ) -> Vec<ResultMessage> {
+        let mut connection = self.connect().await;
+        send_text(&mut connection, message).await;
+        let mut results = Vec::new();
         loop {
-            match connection.next().await {
-                Some(Ok(awc::ws::Frame::Text(msg))) => {
-                    let msg = serde_json::from_slice::<ResultMessage>(&msg)
-                        .expect(&format!("Failed to parse JSON: {:?}", msg));
-                    tracing::info!("RESULT: {:?}", msg);
-                    return msg;
-                }
-                Some(Ok(awc::ws::Frame::Ping(_))) => {}
-                err => {
-                    tracing::error!("Receive message: {:?}", err);
-                    panic!("Failed to receive message.");
-                }
+            let result = next_result(&mut connection).await;
+            let last = is_last(&result);
+            results.push(result);
+            if last {
+                return results;
+            }
+        }
+    }
+}
+
+pub async fn send_text(connection: &mut Connection, message: &str) {
+    connection
+        .send(awc::ws::Message::Text(message.to_string().into()))
+        .await
+        .expect("Failed to send message.");
+}
+
+/// Waits for the next text frame, skipping pings and the server hello.
+pub async fn next_json(connection: &mut Connection) -> serde_json::Value {
+    loop {
+        let msg = next_frame_json(connection).await;
+        if msg["type"] != "hello" {
+            return msg;
+        }
+    }
+}
+
+/// Waits for the next text frame, skipping pings.
+pub async fn next_frame_json(connection: &mut Connection) -> serde_json::Value {
+    loop {
+        match connection.next().await {
+            Some(Ok(awc::ws::Frame::Text(msg))) => {
+                let msg = serde_json::from_slice::<serde_json::Value>(&msg)
+                    .unwrap_or_else(|_| panic!("Failed to parse JSON: {:?}", msg));
+                tracing::info!("RESULT: {:?}", msg);
+                return msg;
+            }
+            Some(Ok(awc::ws::Frame::Ping(_))) => {}
+            err => {
+                tracing::error!("Receive message: {:?}", err);
+                panic!("Failed to receive message.");
             }
         }
     }
 }
 
+/// Waits for the next result message, skipping pings.
+pub async fn next_result(connection: &mut Connection) -> ResultMessage {
+    let msg = next_json(connection).await;
+    serde_json::from_value::<ResultMessage>(msg.clone())
+        .unwrap_or_else(|_| panic!("Failed to parse result: {:?}", msg))
+}
+
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Spawns the app after applying `customize` to the test configuration.
+pub async fn spawn_app_with(customize: impl FnOnce(&mut Settings)) -> TestApp {
+    spawn_app_with_layers(customize, TaskLayers::default()).await
+}
+
+/// Spawns the app with `layers` around the subsystem tasks.
+pub async fn spawn_app_with_layers(
+    customize: impl FnOnce(&mut Settings),
+    layers: TaskLayers,
+) -> TestApp {
     // Set up tracing
     Lazy::force(&TRACING);
 
@@ -76,18 +151,24 @@ pub async fn spawn_app() -> TestApp {
         c.port = 0;
         c.websocket.heartbeat_interval = Duration::from_millis(50);
         c.websocket.client_timeout = Duration::from_millis(250);
+        // Jobs are only kept in memory, unless a test opts in
+        c.subsystems.jobs.store = None;
+        customize(&mut c);
         c
     };
 
     // Launch app as background task
-    let application = Application::build(configuration).expect("Failed to build application.");
+    let application = Application::build_with_layers(configuration, layers)
+        .expect("Failed to build application.");
     let application_port = application.port();
-    // let _ = tokio::spawn(application.run_until_stopped());
-    let _a = tokio::spawn(async move { application.run_until_stopped().await });
+    let shutdown = application.shutdown_handle();
+    let server = tokio::spawn(application.run_until_stopped());
 
     let test_app = TestApp {
         address: format!("http://127.0.0.1:{}", application_port),
         port: application_port,
+        shutdown,
+        server,
     };
 
     test_app
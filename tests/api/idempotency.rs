@@ -0,0 +1,239 @@
+use crate::helpers::{next_result, send_text, spawn_app_with_layers, TestApp};
+use awc::Client;
+use axum_websockets::{
+    configuration::Settings,
+    message::{ErrorCode, ErrorPayload},
+    service::{TaskLayers, TaskRequest},
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tower::util::MapRequestLayer;
+
+const GET_FILES: &str = r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples", "idempotency_key": "k1"}"#;
+const CPU_LOAD: &str =
+    r#"{"system": "pc_usage", "task": "cpu_load", "idempotency_key": "k1", "id": 1}"#;
+
+/// Spawns an app counting the tasks that actually run.
+async fn spawn_counting(customize: impl FnOnce(&mut Settings)) -> (TestApp, Arc<AtomicUsize>) {
+    let count = Arc::new(AtomicUsize::new(0));
+    let layer = {
+        let count = count.clone();
+        MapRequestLayer::new(move |req: TaskRequest| {
+            count.fetch_add(1, Ordering::SeqCst);
+            req
+        })
+    };
+    let app = spawn_app_with_layers(
+        |c| {
+            c.websocket.client_timeout = Duration::from_secs(10);
+            customize(c);
+        },
+        // Inside the idempotency layer, so replayed results are not counted
+        TaskLayers::default().nest(TaskLayers::default().layer(layer)),
+    )
+    .await;
+    (app, count)
+}
+
+#[actix_rt::test]
+async fn retried_request_replays_the_first_result() {
+    // Arrange
+    let (app, count) = spawn_counting(|_| {}).await;
+    let first = app.get_first_result(GET_FILES).await;
+
+    // Act
+    let retry = app.get_first_result(GET_FILES).await;
+
+    // Assert
+    assert!(retry.success, "Failed: {:?}", retry.payload);
+    assert_eq!(retry.payload, first.payload);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+}
+
+#[actix_rt::test]
+async fn requests_without_a_key_always_run() {
+    // Arrange
+    let (app, count) = spawn_counting(|_| {}).await;
+    let message = r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples"}"#;
+    app.get_first_result(message).await;
+
+    // Act
+    app.get_first_result(message).await;
+
+    // Assert
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[actix_rt::test]
+async fn keys_are_scoped_to_the_identity() {
+    // Arrange
+    let (app, count) = spawn_counting(|_| {}).await;
+
+    // Act
+    for user in ["alice", "bob"] {
+        let (_, mut connection) = Client::new()
+            .ws(format!("{}/ws", app.address))
+            .header("x-forwarded-user", user)
+            .connect()
+            .await
+            .expect("Failed to connect.");
+        send_text(&mut connection, GET_FILES).await;
+        let result = next_result(&mut connection).await;
+        assert!(result.success, "Failed: {:?}", result.payload);
+    }
+
+    // Assert
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[actix_rt::test]
+async fn session_results_are_not_replayed_to_other_anonymous_sessions() {
+    // Arrange
+    let (app, _) = spawn_counting(|_| {}).await;
+    let get = r#"{"system": "session", "task": "get", "payload": {"key": "name"}, "idempotency_key": "1"}"#;
+    let mut first = app.connect().await;
+    send_text(
+        &mut first,
+        r#"{"system": "session", "task": "set", "payload": {"key": "name", "value": "alice"}}"#,
+    )
+    .await;
+    next_result(&mut first).await;
+    send_text(&mut first, get).await;
+    let original = next_result(&mut first).await;
+    assert_eq!(original.payload, "alice");
+
+    // Act
+    let result = app.get_first_result(get).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(result.payload, serde_json::Value::Null);
+}
+
+#[actix_rt::test]
+async fn reusing_a_key_for_another_request_fails() {
+    // Arrange
+    let (app, count) = spawn_counting(|_| {}).await;
+    app.get_first_result(GET_FILES).await;
+    let other = r#"{"system": "python_repo", "task": "get_files", "payload": "src", "idempotency_key": "k1"}"#;
+
+    // Act
+    let result = app.get_first_result(other).await;
+
+    // Assert
+    assert!(!result.success, "Key reused for another request.");
+    let error = serde_json::from_value::<ErrorPayload>(result.payload).expect("Not an error.");
+    assert_eq!(error.code, ErrorCode::InvalidRequest);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+}
+
+#[actix_rt::test]
+async fn results_are_forgotten_after_the_window() {
+    // Arrange
+    let (app, count) = spawn_counting(|c| c.idempotency.window = Duration::from_millis(50)).await;
+    app.get_first_result(GET_FILES).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Act
+    app.get_first_result(GET_FILES).await;
+
+    // Assert
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[actix_rt::test]
+async fn retry_waits_for_the_running_request() {
+    // Arrange
+    let (app, count) = spawn_counting(|c| {
+        c.subsystems.pc_usage.sample_duration = Duration::from_millis(300);
+    })
+    .await;
+    let mut first = app.connect().await;
+    let mut retry = app.connect().await;
+    send_text(&mut first, CPU_LOAD).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Act
+    send_text(&mut retry, CPU_LOAD).await;
+    let retried = next_result(&mut retry).await;
+    let original = next_result(&mut first).await;
+
+    // Assert
+    assert!(retried.success, "Failed: {:?}", retried.payload);
+    assert_eq!(retried.payload, original.payload);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+}
+
+#[actix_rt::test]
+async fn retry_runs_the_task_when_the_first_request_was_aborted() {
+    // Arrange
+    let (app, count) = spawn_counting(|c| {
+        c.subsystems.pc_usage.sample_duration = Duration::from_millis(300);
+    })
+    .await;
+    let mut first = app.connect().await;
+    send_text(&mut first, CPU_LOAD).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(first);
+
+    // Act
+    let result = app.get_first_result(CPU_LOAD).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[actix_rt::test]
+async fn failed_results_are_not_replayed() {
+    // Arrange
+    let (app, count) = spawn_counting(|_| {}).await;
+    let with_timeout = |timeout: u64| {
+        serde_json::json!({
+            "system": "pc_usage",
+            "task": "cpu_load",
+            "idempotency_key": "k1",
+            "timeout": timeout,
+        })
+        .to_string()
+    };
+    let timed_out = app.get_first_result(&with_timeout(1)).await;
+    let error = serde_json::from_value::<ErrorPayload>(timed_out.payload).expect("Not an error.");
+    assert_eq!(error.code, ErrorCode::Timeout);
+
+    // Act
+    let retry = app.get_first_result(&with_timeout(5000)).await;
+
+    // Assert
+    assert!(retry.success, "Timeout was replayed: {:?}", retry.payload);
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[actix_rt::test]
+async fn aborted_requests_do_not_hold_entries() {
+    // Arrange
+    let (app, count) = spawn_counting(|c| {
+        c.idempotency.max_entries = 1;
+        c.subsystems.pc_usage.sample_duration = Duration::from_millis(300);
+    })
+    .await;
+    let mut aborted = app.connect().await;
+    send_text(&mut aborted, CPU_LOAD).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(aborted);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let message = r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples", "idempotency_key": "k2"}"#;
+
+    // Act
+    app.get_first_result(message).await;
+    let retry = app.get_first_result(message).await;
+
+    // Assert
+    assert!(retry.success, "Failed: {:?}", retry.payload);
+    assert_eq!(count.load(Ordering::SeqCst), 2, "Retry was not replayed.");
+}
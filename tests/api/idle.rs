@@ -0,0 +1,115 @@
+use crate::helpers::{send_text, spawn_app_with, Connection};
+use axum_websockets::idle::{IdleWarning, IDLE_CLOSE_CODE};
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+
+#[derive(Debug)]
+enum Event {
+    Text(serde_json::Value),
+    Close(Option<u16>),
+}
+
+/// Waits for the next text or close frame, answering pings.
+async fn next_event(connection: &mut Connection) -> Event {
+    loop {
+        match connection.next().await {
+            Some(Ok(awc::ws::Frame::Ping(msg))) => {
+                connection
+                    .send(awc::ws::Message::Pong(msg))
+                    .await
+                    .expect("Failed to send Pong message.");
+            }
+            Some(Ok(awc::ws::Frame::Text(msg))) => {
+                let msg = serde_json::from_slice::<serde_json::Value>(&msg)
+                    .expect("Failed to parse JSON.");
+                if msg["type"] != "hello" {
+                    return Event::Text(msg);
+                }
+            }
+            Some(Ok(awc::ws::Frame::Close(reason))) => {
+                return Event::Close(reason.map(|r| u16::from(r.code)))
+            }
+            None => return Event::Close(None),
+            _ => {}
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn idle_session_is_warned_then_closed() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.idle_timeout = Duration::from_millis(300);
+        c.websocket.idle_warning = Duration::from_millis(150);
+    })
+    .await;
+    let mut connection = app.connect().await;
+
+    // Act
+    let warning = next_event(&mut connection).await;
+    let close = next_event(&mut connection).await;
+
+    // Assert
+    match warning {
+        Event::Text(msg) => {
+            let warning = serde_json::from_value::<IdleWarning>(msg).expect("Not a warning.");
+            assert!(warning.closes_in <= Duration::from_millis(150));
+        }
+        other => panic!("Expected an idle warning: {:?}", other),
+    }
+    match close {
+        Event::Close(code) => assert_eq!(code, Some(IDLE_CLOSE_CODE)),
+        other => panic!("Expected a close frame: {:?}", other),
+    }
+}
+
+#[actix_rt::test]
+async fn requests_keep_session_active() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.idle_timeout = Duration::from_millis(300);
+        c.websocket.idle_warning = Duration::from_millis(100);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    let request = r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples"}"#;
+
+    // Act
+    for _ in 0..6 {
+        send_text(&mut connection, request).await;
+        let event = next_event(&mut connection).await;
+
+        // Assert
+        match event {
+            Event::Text(msg) => assert_eq!(msg["success"], true, "Unexpected message: {}", msg),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[actix_rt::test]
+async fn running_tasks_are_exempt_from_idle_timeout() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.idle_timeout = Duration::from_millis(100);
+        c.websocket.idle_warning = Duration::from_millis(20);
+    })
+    .await;
+    let mut connection = app.connect().await;
+
+    // Act
+    // Samples the cpu load for 200ms
+    send_text(
+        &mut connection,
+        r#"{"system": "pc_usage", "task": "cpu_load"}"#,
+    )
+    .await;
+    let event = next_event(&mut connection).await;
+
+    // Assert
+    match event {
+        Event::Text(msg) => assert_eq!(msg["system"], "pc_usage", "Unexpected message: {}", msg),
+        other => panic!("Unexpected event: {:?}", other),
+    }
+}
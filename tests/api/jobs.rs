@@ -0,0 +1,287 @@
+use crate::helpers::{next_result, send_text, spawn_app_with, Connection, TestApp};
+use awc::Client;
+use axum_websockets::{
+    configuration::{RestartPolicy, Settings},
+    message::ResultMessage,
+    subsystems::WebsocketSystem,
+};
+use futures::SinkExt;
+use std::{path::Path, time::Duration};
+use tempfile::TempDir;
+
+const GET_FILES: &str = r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples", "background": true}"#;
+const CPU_LOAD: &str = r#"{"system": "pc_usage", "task": "cpu_load", "background": true}"#;
+
+/// Submits a background job, returning its ID.
+async fn submit(connection: &mut Connection, message: &str) -> String {
+    send_text(connection, message).await;
+    let result = next_result(connection).await;
+    assert!(result.success, "Failed: {:?}", result.payload);
+    result.payload["job_id"]
+        .as_str()
+        .expect("Missing job ID.")
+        .to_string()
+}
+
+async fn jobs(connection: &mut Connection, task: &str, id: &str) -> ResultMessage {
+    let message = serde_json::json!({
+        "system": "jobs",
+        "task": task,
+        "payload": {"id": id},
+    })
+    .to_string();
+    send_text(connection, &message).await;
+    next_result(connection).await
+}
+
+/// Polls the job status until it is no longer running.
+async fn wait_for(connection: &mut Connection, id: &str) -> String {
+    loop {
+        let status = jobs(connection, "status", id).await;
+        assert!(status.success, "Failed: {:?}", status.payload);
+        let status = status.payload["status"].as_str().unwrap().to_string();
+        if status != "running" {
+            return status;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Temporary directory for the job store, removed with the store when dropped.
+fn store_dir() -> TempDir {
+    TempDir::new().expect("Failed to create directory.")
+}
+
+/// Spawns an app recording its jobs in `store`.
+async fn spawn_with_store(store: &Path, customize: impl FnOnce(&mut Settings)) -> TestApp {
+    let store = store.to_path_buf();
+    spawn_app_with(move |c| {
+        c.subsystems.jobs.store = Some(store);
+        customize(c);
+    })
+    .await
+}
+
+/// Stops the app and waits for the server to exit.
+async fn stop(app: TestApp) {
+    app.shutdown.trigger();
+    tokio::time::timeout(Duration::from_secs(2), app.server)
+        .await
+        .expect("Server did not stop.")
+        .expect("Server task failed.")
+        .expect("Server failed.");
+}
+
+/// Submits a job that is still running when the app stops, returning its ID.
+async fn interrupted_job(store: &Path) -> String {
+    let app = spawn_with_store(store, |c| {
+        c.subsystems.pc_usage.sample_duration = Duration::from_secs(10);
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    let id = submit(&mut connection, CPU_LOAD).await;
+    drop(connection);
+    stop(app).await;
+    id
+}
+
+#[actix_rt::test]
+async fn job_result_can_be_fetched_from_another_session() {
+    // Arrange
+    let app = spawn_app_with(|_| {}).await;
+    let mut first = app.connect().await;
+    let id = submit(&mut first, GET_FILES).await;
+    drop(first);
+    let mut second = app.connect().await;
+
+    // Act
+    let status = wait_for(&mut second, &id).await;
+    let result = jobs(&mut second, "result", &id).await;
+
+    // Assert
+    assert_eq!(status, "succeeded");
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(result.payload["success"], true);
+    assert_eq!(result.payload["payload"].as_array().map(Vec::len), Some(4));
+}
+
+#[actix_rt::test]
+async fn job_keeps_running_after_disconnect() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.subsystems.pc_usage.sample_duration = Duration::from_millis(300);
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut first = app.connect().await;
+    let id = submit(&mut first, CPU_LOAD).await;
+    first
+        .close()
+        .await
+        .expect("Failed to close the connection.");
+    let mut second = app.connect().await;
+
+    // Act
+    let status = wait_for(&mut second, &id).await;
+
+    // Assert
+    assert_eq!(status, "succeeded");
+}
+
+#[actix_rt::test]
+async fn running_job_can_be_cancelled() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.subsystems.pc_usage.sample_duration = Duration::from_secs(10);
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    let id = submit(&mut connection, CPU_LOAD).await;
+    let early = jobs(&mut connection, "result", &id).await;
+
+    // Act
+    let cancelled = jobs(&mut connection, "cancel", &id).await;
+    let result = jobs(&mut connection, "result", &id).await;
+
+    // Assert
+    assert!(!early.success, "Got the result of a running job.");
+    assert_eq!(cancelled.payload["status"], "cancelled");
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(result.payload["payload"], serde_json::Value::Null);
+}
+
+#[actix_rt::test]
+async fn jobs_are_hidden_from_other_identities() {
+    // Arrange
+    let app = spawn_app_with(|_| {}).await;
+    let connect = |user: &'static str| {
+        Client::new()
+            .ws(format!("{}/ws", app.address))
+            .header("x-forwarded-user", user)
+            .connect()
+    };
+    let (_, mut alice) = connect("alice").await.expect("Failed to connect.");
+    let (_, mut bob) = connect("bob").await.expect("Failed to connect.");
+    let id = submit(&mut alice, GET_FILES).await;
+
+    // Act
+    let status = jobs(&mut bob, "status", &id).await;
+    let list = jobs(&mut bob, "list", &id).await;
+
+    // Assert
+    assert!(!status.success, "Job visible to another identity.");
+    assert_eq!(list.payload, serde_json::json!([]));
+}
+
+#[actix_rt::test]
+async fn anonymous_jobs_are_hidden_from_other_sessions() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.subsystems.pc_usage.sample_duration = Duration::from_secs(10);
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut first = app.connect().await;
+    let mut second = app.connect().await;
+    let id = submit(&mut first, CPU_LOAD).await;
+
+    // Act
+    let list = jobs(&mut second, "list", &id).await;
+    let cancelled = jobs(&mut second, "cancel", &id).await;
+    let status = jobs(&mut first, "status", &id).await;
+
+    // Assert
+    assert_eq!(list.payload, serde_json::json!([]));
+    assert!(!cancelled.success, "Job cancelled by another session.");
+    assert_eq!(status.payload["status"], "running");
+}
+
+#[actix_rt::test]
+async fn finished_jobs_are_dropped_after_retention() {
+    // Arrange
+    // Well above the polling interval of `wait_for`, so the job is still there when it finishes
+    let retention = Duration::from_millis(1000);
+    let app = spawn_app_with(|c| {
+        c.subsystems.jobs.retention = retention;
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    let id = submit(&mut connection, GET_FILES).await;
+    assert_eq!(wait_for(&mut connection, &id).await, "succeeded");
+    tokio::time::sleep(retention + Duration::from_millis(200)).await;
+
+    // Act
+    let status = jobs(&mut connection, "status", &id).await;
+
+    // Assert
+    assert!(!status.success, "Job was retained.");
+}
+
+#[actix_rt::test]
+async fn finished_jobs_survive_restarts() {
+    // Arrange
+    let dir = store_dir();
+    let store = dir.path().join("jobs.jsonl");
+    let app = spawn_with_store(&store, |_| {}).await;
+    let mut connection = app.connect().await;
+    let id = submit(&mut connection, GET_FILES).await;
+    wait_for(&mut connection, &id).await;
+    drop(connection);
+    stop(app).await;
+
+    // Act
+    let app = spawn_with_store(&store, |_| {}).await;
+    let mut connection = app.connect().await;
+    let result = jobs(&mut connection, "result", &id).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(result.payload["job"]["status"], "succeeded");
+    assert_eq!(result.payload["payload"].as_array().map(Vec::len), Some(4));
+}
+
+#[actix_rt::test]
+async fn interrupted_jobs_fail_on_restart_by_default() {
+    // Arrange
+    let dir = store_dir();
+    let store = dir.path().join("jobs.jsonl");
+    let id = interrupted_job(&store).await;
+
+    // Act
+    let app = spawn_with_store(&store, |_| {}).await;
+    let mut connection = app.connect().await;
+    let result = jobs(&mut connection, "result", &id).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(result.payload["job"]["status"], "failed");
+    assert_eq!(result.payload["success"], false);
+}
+
+#[actix_rt::test]
+async fn interrupted_jobs_are_requeued_by_their_restart_policy() {
+    // Arrange
+    let dir = store_dir();
+    let store = dir.path().join("jobs.jsonl");
+    let id = interrupted_job(&store).await;
+
+    // Act
+    let app = spawn_with_store(&store, |c| {
+        c.subsystems.pc_usage.sample_duration = Duration::from_millis(100);
+        c.subsystems
+            .jobs
+            .restart_policies
+            .entry(WebsocketSystem::PcUsage)
+            .or_default()
+            .insert("cpu_load".to_string(), RestartPolicy::Requeue);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    let status = wait_for(&mut connection, &id).await;
+
+    // Assert
+    assert_eq!(status, "succeeded");
+}
@@ -0,0 +1,183 @@
+use crate::helpers::{next_json, send_text, spawn_app, Connection};
+use awc::Client;
+use axum_websockets::jsonrpc::{
+    Response, INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR, TASK_FAILED,
+};
+
+async fn call(connection: &mut Connection, message: serde_json::Value) -> serde_json::Value {
+    send_text(connection, &message.to_string()).await;
+    next_json(connection).await
+}
+
+fn error_code(response: serde_json::Value) -> i64 {
+    serde_json::from_value::<Response>(response)
+        .expect("Failed to deserialize response.")
+        .error
+        .expect("Expected an error response.")
+        .code
+}
+
+#[actix_rt::test]
+async fn jsonrpc_route_receives_spec_compliant_result() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect_to("/jsonrpc").await;
+
+    // Act
+    let response = call(
+        &mut connection,
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "python_repo.get_files",
+            "params": "tests/examples",
+            "id": 1
+        }),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(response["jsonrpc"], "2.0");
+    assert_eq!(response["id"], 1);
+    assert!(response.get("error").is_none(), "Unexpected error.");
+    assert_eq!(response["result"].as_array().map(Vec::len), Some(4));
+}
+
+#[actix_rt::test]
+async fn jsonrpc_is_selected_by_subprotocol() {
+    // Arrange
+    let app = spawn_app().await;
+    let (response, mut connection) = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .protocols(["jsonrpc-2.0"])
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+
+    // Act
+    let result = call(
+        &mut connection,
+        serde_json::json!({"jsonrpc": "2.0", "method": "pc_usage.cpu_load", "id": "cpu"}),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(
+        response.headers().get("sec-websocket-protocol").unwrap(),
+        "jsonrpc-2.0"
+    );
+    assert_eq!(result["id"], "cpu");
+    assert!(result["result"].is_array(), "Expected cpu load result.");
+}
+
+#[actix_rt::test]
+async fn jsonrpc_errors_use_standard_codes() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect_to("/jsonrpc").await;
+    let cases = [
+        (serde_json::json!("{not json"), PARSE_ERROR),
+        (
+            serde_json::json!({"method": "pc_usage.cpu_load", "id": 1}),
+            INVALID_REQUEST,
+        ),
+        (
+            serde_json::json!({"jsonrpc": "2.0", "method": "unknown.cpu_load", "id": 1}),
+            METHOD_NOT_FOUND,
+        ),
+        (
+            serde_json::json!({"jsonrpc": "2.0", "method": "pc_usage.unknown", "id": 1}),
+            METHOD_NOT_FOUND,
+        ),
+        (
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "python_repo.get_files",
+                "params": "tests/some_incorrect_path",
+                "id": 1
+            }),
+            TASK_FAILED,
+        ),
+    ];
+
+    for (message, expected_code) in cases {
+        // Act
+        let text = match message {
+            serde_json::Value::String(text) => text,
+            message => message.to_string(),
+        };
+        send_text(&mut connection, &text).await;
+        let response = next_json(&mut connection).await;
+
+        // Assert
+        assert_eq!(error_code(response), expected_code, "Request: {}", text);
+    }
+}
+
+#[actix_rt::test]
+async fn jsonrpc_batch_skips_notifications() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect_to("/jsonrpc").await;
+
+    // Act
+    let response = call(
+        &mut connection,
+        serde_json::json!([
+            {"jsonrpc": "2.0", "method": "pc_usage.cpu_load", "id": 1},
+            {"jsonrpc": "2.0", "method": "pc_usage.cpu_load"},
+            {"jsonrpc": "2.0", "method": "python_repo.get_files", "params": "tests/examples", "id": 2},
+        ]),
+    )
+    .await;
+
+    // Assert
+    let responses = response.as_array().expect("Expected a batch response.");
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0]["id"], 1);
+    assert_eq!(responses[1]["id"], 2);
+}
+
+#[actix_rt::test]
+async fn jsonrpc_notifications_are_never_answered() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect_to("/jsonrpc").await;
+    send_text(
+        &mut connection,
+        &serde_json::json!({"jsonrpc": "2.0", "method": "unknown.cpu_load"}).to_string(),
+    )
+    .await;
+    send_text(
+        &mut connection,
+        &serde_json::json!({"jsonrpc": "2.0", "method": "pc_usage.unknown"}).to_string(),
+    )
+    .await;
+
+    // Act
+    let response = call(
+        &mut connection,
+        serde_json::json!({"jsonrpc": "2.0", "method": "pc_usage.cpu_load", "id": 1}),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(response["id"], 1, "Notification answered: {}", response);
+}
+
+#[actix_rt::test]
+async fn jsonrpc_null_id_is_a_request() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect_to("/jsonrpc").await;
+
+    // Act
+    let response = call(
+        &mut connection,
+        serde_json::json!({"jsonrpc": "2.0", "method": "pc_usage.cpu_load", "id": null}),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(response["id"], serde_json::Value::Null);
+    assert!(response["result"].is_array(), "Expected cpu load result.");
+}
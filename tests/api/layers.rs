@@ -0,0 +1,176 @@
+use crate::helpers::{next_result, send_text, spawn_app_with_layers};
+use awc::Client;
+use axum_websockets::{
+    message::{ErrorCode, ErrorPayload},
+    service::{identity::RequireIdentityLayer, TaskLayers, TaskRequest},
+    subsystems::WebsocketSystem,
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tower::util::MapRequestLayer;
+
+const GET_FILES: &str =
+    r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples"}"#;
+const CPU_LOAD: &str = r#"{"system": "pc_usage", "task": "cpu_load"}"#;
+
+/// Layer counting the tasks going through it.
+fn counter() -> (
+    Arc<AtomicUsize>,
+    MapRequestLayer<impl Fn(TaskRequest) -> TaskRequest + Clone + Send + Sync>,
+) {
+    let count = Arc::new(AtomicUsize::new(0));
+    let layer = {
+        let count = count.clone();
+        MapRequestLayer::new(move |req: TaskRequest| {
+            count.fetch_add(1, Ordering::SeqCst);
+            req
+        })
+    };
+    (count, layer)
+}
+
+#[actix_rt::test]
+async fn global_layer_wraps_every_subsystem() {
+    // Arrange
+    let (count, layer) = counter();
+    let app = spawn_app_with_layers(|_| {}, TaskLayers::default().layer(layer)).await;
+
+    // Act
+    let files = app.get_first_result(GET_FILES).await;
+    let load = app.get_first_result(CPU_LOAD).await;
+
+    // Assert
+    assert!(files.success && load.success, "Tasks failed.");
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[actix_rt::test]
+async fn subsystem_layer_only_wraps_its_subsystem() {
+    // Arrange
+    let (count, layer) = counter();
+    let layers = TaskLayers::default().layer_for(WebsocketSystem::PcUsage, layer);
+    let app = spawn_app_with_layers(|_| {}, layers).await;
+
+    // Act
+    app.get_first_result(GET_FILES).await;
+    app.get_first_result(CPU_LOAD).await;
+
+    // Assert
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+}
+
+#[actix_rt::test]
+async fn layers_can_rewrite_requests() {
+    // Arrange
+    let layer = MapRequestLayer::new(|mut req: TaskRequest| {
+        req.payload = serde_json::json!("tests/examples");
+        req
+    });
+    let layers = TaskLayers::default().layer_for(WebsocketSystem::PythonRepo, layer);
+    let app = spawn_app_with_layers(|_| {}, layers).await;
+    let message = r#"{"system": "python_repo", "task": "get_files", "payload": "does/not/exist"}"#;
+
+    // Act
+    let result = app.get_first_result(message).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(result.payload.as_array().map(Vec::len), Some(4));
+}
+
+#[actix_rt::test]
+async fn require_identity_rejects_anonymous_sessions() {
+    // Arrange
+    let layers = TaskLayers::default().layer(RequireIdentityLayer);
+    let app = spawn_app_with_layers(|_| {}, layers).await;
+
+    // Act
+    let result = app.get_first_result(GET_FILES).await;
+
+    // Assert
+    assert!(!result.success, "Anonymous task succeeded.");
+    let error = serde_json::from_value::<ErrorPayload>(result.payload).expect("Not an error.");
+    assert_eq!(error.code, ErrorCode::Unauthorized);
+}
+
+#[actix_rt::test]
+async fn require_identity_accepts_identified_sessions() {
+    // Arrange
+    let layers = TaskLayers::default().layer(RequireIdentityLayer);
+    let app = spawn_app_with_layers(|_| {}, layers).await;
+    let (_response, mut connection) = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .header("x-forwarded-user", "alice")
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+
+    // Act
+    send_text(&mut connection, GET_FILES).await;
+    let result = next_result(&mut connection).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+}
+
+#[actix_rt::test]
+async fn metrics_count_tasks() {
+    // Arrange
+    let app = spawn_app_with_layers(|_| {}, TaskLayers::default()).await;
+    app.get_first_result(GET_FILES).await;
+    app.get_first_result(r#"{"system": "python_repo", "task": "made_up"}"#)
+        .await;
+
+    // Act
+    let body = Client::new()
+        .get(format!("{}/metrics", app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .body()
+        .await
+        .expect("Failed to read metrics.");
+    let metrics = String::from_utf8_lossy(&body);
+
+    // Assert
+    assert!(
+        metrics.contains(r#"ws_tasks_total{system="python_repo",task="get_files"} 1"#),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains(r#"ws_tasks_failed_total{system="python_repo",task="unknown"} 1"#),
+        "{}",
+        metrics
+    );
+}
+
+#[actix_rt::test]
+async fn metrics_do_not_label_tasks_rejected_before_parsing() {
+    // Arrange
+    let app =
+        spawn_app_with_layers(|_| {}, TaskLayers::default().layer(RequireIdentityLayer)).await;
+    app.get_first_result(r#"{"system": "python_repo", "task": "a\"b\\c\nd"}"#)
+        .await;
+
+    // Act
+    let body = Client::new()
+        .get(format!("{}/metrics", app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .body()
+        .await
+        .expect("Failed to read metrics.");
+    let metrics = String::from_utf8_lossy(&body);
+
+    // Assert
+    assert!(
+        metrics.contains(r#"ws_tasks_failed_total{system="python_repo",task="unknown"} 1"#),
+        "{}",
+        metrics
+    );
+    assert!(!metrics.contains("a\\\"b"), "{}", metrics);
+}
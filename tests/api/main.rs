@@ -1,4 +1,27 @@
+// Existing tests compare lengths to zero
+#![allow(clippy::len_zero)]
+
+mod batch;
+mod cache;
+mod connection_limits;
+mod fair_queue;
+mod graphql;
+mod handshake;
 mod heartbeat;
 mod helpers;
+mod idempotency;
+mod idle;
+mod jobs;
+mod jsonrpc;
+mod layers;
+mod message_size;
 mod pc_usage;
+mod pipeline;
+mod progress;
 mod python_repo;
+mod scheduler;
+mod session;
+mod shutdown;
+mod streaming;
+mod timeouts;
+mod workers;
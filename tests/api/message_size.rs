@@ -0,0 +1,144 @@
+use crate::helpers::{send_text, spawn_app, spawn_app_with, Connection};
+use awc::Client;
+use axum_websockets::message::{ErrorCode, ErrorPayload};
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+
+/// Waits for the connection to be closed by the server, failing on any message but the hello.
+async fn assert_closed(connection: &mut Connection) {
+    let closed = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            match connection.next().await {
+                Some(Ok(awc::ws::Frame::Ping(_))) => {}
+                Some(Ok(awc::ws::Frame::Text(text)))
+                    if serde_json::from_slice::<serde_json::Value>(&text)
+                        .is_ok_and(|msg| msg["type"] == "hello") => {}
+                Some(Ok(awc::ws::Frame::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(frame)) => panic!("Got {:?} instead of a close.", frame),
+            }
+        }
+    })
+    .await;
+    assert!(closed.is_ok(), "Connection was not closed.");
+}
+
+#[actix_rt::test]
+async fn oversized_message_receives_structured_error() {
+    // Arrange
+    let app = spawn_app_with(|c| c.websocket.max_inbound_message_size = 128).await;
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "x".repeat(256)
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(!result.success, "Call should not success.");
+    let error = serde_json::from_value::<ErrorPayload>(result.payload)
+        .expect("Failed to deserialize error payload.");
+    assert_eq!(error.code, ErrorCode::MessageTooLarge);
+}
+
+#[actix_rt::test]
+async fn frame_over_the_transport_limit_closes_the_connection() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.max_inbound_message_size = 128;
+        c.websocket.max_inbound_frame_size = 1024;
+        // Only the oversized frame may close the connection
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "x".repeat(4096)
+    })
+    .to_string();
+
+    // Act
+    send_text(&mut connection, &message).await;
+
+    // Assert
+    assert_closed(&mut connection).await;
+}
+
+#[actix_rt::test]
+async fn binary_and_graphql_frames_are_limited() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.max_inbound_message_size = 128;
+        c.websocket.max_inbound_frame_size = 1024;
+        // Only the oversized frame may close the connection
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut binary = app.connect().await;
+    let (_response, mut graphql) = Client::new()
+        .ws(format!("{}/graphql", app.address))
+        .protocols(["graphql-transport-ws"])
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+
+    // Act
+    binary
+        .send(awc::ws::Message::Binary(vec![0; 4096].into()))
+        .await
+        .expect("Failed to send binary message.");
+    send_text(&mut graphql, &"x".repeat(4096)).await;
+
+    // Assert
+    assert_closed(&mut binary).await;
+    assert_closed(&mut graphql).await;
+}
+
+#[actix_rt::test]
+async fn oversized_result_is_truncated() {
+    // Arrange
+    let app = spawn_app_with(|c| c.websocket.max_outbound_message_size = 100).await;
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "tests/examples"
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(result.success, "Call was not successful.");
+    assert!(result.truncated, "Result should be truncated.");
+    let files = result
+        .payload
+        .as_array()
+        .expect("Payload should be an array.");
+    assert!(!files.is_empty(), "Empty results.");
+    assert!(files.len() < 4, "Result was not truncated.");
+}
+
+#[actix_rt::test]
+async fn results_within_limit_are_not_truncated() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "tests/examples"
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(result.success, "Call was not successful.");
+    assert!(!result.truncated, "Result should not be truncated.");
+    assert_eq!(result.payload.as_array().map(Vec::len), Some(4));
+}
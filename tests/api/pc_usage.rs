@@ -1,5 +1,6 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with};
 use axum_websockets::subsystems::{pc_usage::CpuLoadResult, WebsocketSystem};
+use std::time::{Duration, Instant};
 
 #[actix_rt::test]
 async fn cpu_load_receives_results() {
@@ -39,3 +40,32 @@ async fn receive_error_on_invalid_task_name() {
     assert_eq!(result.system.unwrap(), WebsocketSystem::PcUsage);
     assert!(!result.success, "Call should not success.");
 }
+
+#[actix_rt::test]
+async fn cpu_load_sample_is_shared_between_sessions() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.subsystems.pc_usage.sample_duration = Duration::from_millis(500);
+        c.subsystems.pc_usage.sample_ttl = Duration::from_secs(60);
+        // Keep the session alive without answering pings
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let message = serde_json::json!({
+        "system": "pc_usage",
+        "task":  "cpu_load",
+    })
+    .to_string();
+    let first = app.get_first_result(&message).await;
+
+    // Act
+    let start = Instant::now();
+    let second = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(
+        start.elapsed() < Duration::from_millis(500),
+        "Second session took a new sample."
+    );
+    assert_eq!(first.payload, second.payload);
+}
@@ -0,0 +1,107 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+use axum_websockets::message::{ErrorCode, ErrorPayload};
+
+const FILES_THEN_STORE: &str = r#"{"id": 7, "pipeline": [
+    {"name": "files", "system": "python_repo", "task": "get_files", "payload": "tests/examples"},
+    {"system": "session", "task": "set", "payload": {"key": "first", "value": {"$ref": "/files/0"}}},
+    {"system": "session", "task": "get", "payload": {"key": "first"}}
+]}"#;
+
+#[actix_rt::test]
+async fn pipeline_passes_results_to_later_steps() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let result = app.get_first_result(FILES_THEN_STORE).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    let steps = result.payload["steps"].as_array().expect("Missing steps.");
+    assert_eq!(steps.len(), 3);
+    assert!(steps.iter().all(|step| step["status"] == "succeeded"));
+    assert_eq!(steps[0]["name"], "files");
+    assert_eq!(steps[2]["payload"], steps[0]["payload"][0]);
+}
+
+#[actix_rt::test]
+async fn pipeline_result_keeps_the_request_id() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let result = app.get_first_result(FILES_THEN_STORE).await;
+
+    // Assert
+    assert_eq!(result.id, Some(serde_json::from_str("7").unwrap()));
+}
+
+#[actix_rt::test]
+async fn steps_can_be_referenced_by_index() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = r#"{"pipeline": [
+        {"system": "python_repo", "task": "get_files", "payload": "tests/examples"},
+        {"system": "session", "task": "set", "payload": {"key": "files", "value": {"$ref": "/0"}}}
+    ]}"#;
+
+    // Act
+    let result = app.get_first_result(message).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+}
+
+#[actix_rt::test]
+async fn failed_step_skips_the_rest_of_the_pipeline() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = r#"{"pipeline": [
+        {"system": "python_repo", "task": "get_files", "payload": "does/not/exist"},
+        {"system": "session", "task": "get"}
+    ]}"#;
+
+    // Act
+    let result = app.get_first_result(message).await;
+
+    // Assert
+    assert!(!result.success, "Pipeline with a failed step succeeded.");
+    let steps = &result.payload["steps"];
+    assert_eq!(steps[0]["status"], "failed");
+    assert!(steps[0]["payload"].is_string(), "Missing step error.");
+    assert_eq!(steps[1]["status"], "skipped");
+}
+
+#[actix_rt::test]
+async fn dangling_reference_fails_its_step() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = r#"{"pipeline": [
+        {"name": "files", "system": "python_repo", "task": "get_files", "payload": "tests/examples"},
+        {"system": "session", "task": "set", "payload": {"key": "x", "value": {"$ref": "/files/99"}}},
+        {"system": "session", "task": "set", "payload": {"key": "y", "value": {"$ref": "/later/0"}}}
+    ]}"#;
+
+    // Act
+    let result = app.get_first_result(message).await;
+
+    // Assert
+    let steps = &result.payload["steps"];
+    assert_eq!(steps[0]["status"], "succeeded");
+    assert_eq!(steps[1]["status"], "failed");
+    assert_eq!(steps[2]["status"], "skipped");
+}
+
+#[actix_rt::test]
+async fn too_long_pipelines_are_rejected() {
+    // Arrange
+    let app = spawn_app_with(|c| c.websocket.max_batch_size = 2).await;
+
+    // Act
+    let result = app.get_first_result(FILES_THEN_STORE).await;
+
+    // Assert
+    assert!(!result.success, "Too long pipeline ran.");
+    let error = serde_json::from_value::<ErrorPayload>(result.payload).expect("Not an error.");
+    assert_eq!(error.code, ErrorCode::BatchTooLarge);
+}
@@ -0,0 +1,70 @@
+use crate::helpers::spawn_app_with;
+use axum_websockets::message::RequestId;
+use std::time::Duration;
+
+#[actix_rt::test]
+async fn get_files_reports_progress_before_the_result() {
+    // Arrange
+    let app = spawn_app_with(|c| c.websocket.progress_interval = Duration::ZERO).await;
+    let message = serde_json::json!({
+        "id": "files",
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "tests/examples",
+        "progress": true
+    })
+    .to_string();
+
+    // Act
+    let results = app.get_results(&message, |r| r.progress.is_none()).await;
+
+    // Assert
+    let (result, progress) = results.split_last().expect("No results.");
+    assert!(result.success, "Call was not successful.");
+    assert!(!progress.is_empty(), "Did not receive any progress.");
+    for msg in progress {
+        assert_eq!(msg.id, Some(RequestId::String("files".into())));
+        assert!(msg.progress.as_ref().unwrap().processed.is_some());
+    }
+    let last_progress = progress.last().unwrap().progress.as_ref().unwrap();
+    assert_eq!(last_progress.processed, Some(4));
+}
+
+#[actix_rt::test]
+async fn progress_reports_are_throttled() {
+    // Arrange
+    let app = spawn_app_with(|c| c.websocket.progress_interval = Duration::from_secs(60)).await;
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "tests/examples",
+        "progress": true
+    })
+    .to_string();
+
+    // Act
+    let results = app.get_results(&message, |r| r.progress.is_none()).await;
+
+    // Assert
+    assert_eq!(results.len(), 2, "Expected a single progress report.");
+    assert!(results[1].success, "Call was not successful.");
+}
+
+#[actix_rt::test]
+async fn progress_is_not_sent_unless_requested() {
+    // Arrange
+    let app = spawn_app_with(|c| c.websocket.progress_interval = Duration::ZERO).await;
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "tests/examples"
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(result.progress.is_none(), "Unexpected progress message.");
+    assert!(result.success, "Call was not successful.");
+}
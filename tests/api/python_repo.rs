@@ -1,5 +1,33 @@
-use crate::helpers::spawn_app;
-use axum_websockets::subsystems::WebsocketSystem;
+use crate::helpers::{next_result, send_text, spawn_app, spawn_app_with};
+use axum_websockets::{
+    message::{ErrorCode, ErrorPayload},
+    subsystems::WebsocketSystem,
+};
+use std::{path::Path, time::Instant};
+use tempfile::TempDir;
+
+/// Directories `width` wide and `depth` deep under `root`, without any file.
+fn empty_tree(root: &Path, width: usize, depth: usize) {
+    if depth == 0 {
+        return;
+    }
+    for i in 0..width {
+        let directory = root.join(i.to_string());
+        std::fs::create_dir(&directory).expect("Failed to create directory.");
+        empty_tree(&directory, width, depth - 1);
+    }
+}
+
+fn walk(path: &str, timeout: u64) -> String {
+    serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": path,
+        "no_cache": true,
+        "timeout": timeout
+    })
+    .to_string()
+}
 
 #[actix_rt::test]
 async fn get_files_receive_python_files_on_valid_path() {
@@ -61,3 +89,89 @@ async fn receive_error_on_invalid_task_name() {
     assert_eq!(result.system.unwrap(), WebsocketSystem::PythonRepo);
     assert!(!result.success, "Call should not success.");
 }
+
+#[actix_rt::test]
+async fn timed_out_walk_frees_the_blocking_pool() {
+    // Arrange
+    let app = spawn_app_with(|c| c.subsystems.python_repo.blocking_walks = 1).await;
+
+    // Act
+    let timed_out = app.get_first_result(&walk(".", 1)).await;
+    let result = app.get_first_result(&walk("tests/examples", 5000)).await;
+
+    // Assert
+    let error = serde_json::from_value::<ErrorPayload>(timed_out.payload)
+        .expect("Failed to deserialize error payload.");
+    assert_eq!(error.code, ErrorCode::Timeout);
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(result.payload.as_array().map(Vec::len), Some(4));
+}
+
+#[actix_rt::test]
+async fn walk_without_matches_stops_at_its_deadline() {
+    // Arrange
+    let app = spawn_app_with(|c| c.subsystems.python_repo.blocking_walks = 1).await;
+    let tree = TempDir::new().expect("Failed to create directory.");
+    empty_tree(tree.path(), 30, 3);
+    let tree = tree.path().to_str().unwrap().to_string();
+    let start = Instant::now();
+    let full = app.get_first_result(&walk(&tree, 60_000)).await;
+    let full_walk = start.elapsed();
+    assert_eq!(full.payload, serde_json::json!([]));
+
+    // Act
+    let timed_out = app.get_first_result(&walk(&tree, 1)).await;
+    let start = Instant::now();
+    // Waits for the blocking pool until the first walk stops
+    let result = app.get_first_result(&walk("tests/examples", 5000)).await;
+    let waited = start.elapsed();
+
+    // Assert
+    let error = serde_json::from_value::<ErrorPayload>(timed_out.payload)
+        .expect("Failed to deserialize error payload.");
+    assert_eq!(error.code, ErrorCode::Timeout);
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert!(
+        waited < full_walk / 2,
+        "Walk went on after its deadline: {:?} of {:?}.",
+        waited,
+        full_walk
+    );
+}
+
+#[actix_rt::test]
+async fn walks_over_the_pool_limit_wait_their_turn() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.subsystems.python_repo.blocking_walks = 1;
+        c.subsystems.python_repo.chunk_size = 1;
+    })
+    .await;
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "tests/examples",
+        "stream": true
+    })
+    .to_string();
+    let mut first = app.connect().await;
+    let mut second = app.connect().await;
+
+    // Act
+    send_text(&mut first, &message).await;
+    send_text(&mut second, &message).await;
+
+    // Assert
+    for connection in [&mut first, &mut second] {
+        let mut files = 0;
+        loop {
+            let result = next_result(connection).await;
+            assert!(result.success, "Failed: {:?}", result.payload);
+            files += result.payload.as_array().map_or(0, Vec::len);
+            if result.chunk.is_none_or(|chunk| chunk.last) {
+                break;
+            }
+        }
+        assert_eq!(files, 4);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::helpers::{next_json, next_result, send_text, spawn_app_with, Connection};
+use axum_websockets::{
+    configuration::{get_configuration, ScheduleSettings, Settings},
+    subsystems::WebsocketSystem,
+    Application,
+};
+use std::time::Duration;
+
+/// Schedule listing the example files every `every`.
+fn files_every(every: Duration) -> ScheduleSettings {
+    ScheduleSettings {
+        name: "files".to_string(),
+        system: WebsocketSystem::PythonRepo,
+        task: "get_files".to_string(),
+        payload: serde_json::json!("tests/examples"),
+        every: Some(every),
+        cron: None,
+    }
+}
+
+fn with_schedule(schedule: ScheduleSettings) -> impl FnOnce(&mut Settings) {
+    move |c| {
+        c.subsystems.jobs.schedules = vec![schedule];
+        c.websocket.client_timeout = Duration::from_secs(10);
+    }
+}
+
+async fn jobs(connection: &mut Connection, task: &str, schedule: &str) -> serde_json::Value {
+    let message = serde_json::json!({
+        "system": "jobs",
+        "task": task,
+        "payload": {"schedule": schedule},
+    })
+    .to_string();
+    send_text(connection, &message).await;
+    let result = next_result(connection).await;
+    assert!(result.success, "Failed: {:?}", result.payload);
+    result.payload
+}
+
+/// Waits for the next pushed schedule result, skipping task results.
+async fn next_schedule_result(connection: &mut Connection) -> serde_json::Value {
+    loop {
+        let msg = next_json(connection).await;
+        if msg["type"] == "schedule_result" {
+            return msg;
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn scheduled_tasks_run_without_sessions() {
+    // Arrange
+    let app = spawn_app_with(with_schedule(files_every(Duration::from_millis(50)))).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let mut connection = app.connect().await;
+
+    // Act
+    let history = jobs(&mut connection, "history", "files").await;
+
+    // Assert
+    let history = history.as_array().expect("History is not an array.");
+    assert!(history.len() >= 2, "Too few runs: {:?}", history);
+    assert_eq!(history[0]["schedule"], "files");
+    assert_eq!(history[0]["job"]["schedule"], "files");
+    assert_eq!(history[0]["success"], true);
+    assert_eq!(history[0]["payload"].as_array().map(Vec::len), Some(4));
+}
+
+#[actix_rt::test]
+async fn scheduled_jobs_cannot_be_cancelled() {
+    // Arrange
+    let app = spawn_app_with(with_schedule(files_every(Duration::from_millis(50)))).await;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let mut connection = app.connect().await;
+    let history = jobs(&mut connection, "history", "files").await;
+    let id = history[0]["job"]["id"].clone();
+
+    // Act
+    send_text(
+        &mut connection,
+        &serde_json::json!({"system": "jobs", "task": "cancel", "payload": {"id": id}}).to_string(),
+    )
+    .await;
+    let result = next_result(&mut connection).await;
+
+    // Assert
+    assert!(!result.success, "Scheduled job was cancelled.");
+}
+
+#[actix_rt::test]
+async fn subscribers_receive_schedule_results() {
+    // Arrange
+    let app = spawn_app_with(with_schedule(files_every(Duration::from_millis(100)))).await;
+    let mut connection = app.connect().await;
+
+    // Act
+    send_text(
+        &mut connection,
+        r#"{"system": "jobs", "task": "subscribe", "payload": {"schedule": "files"}}"#,
+    )
+    .await;
+    let first = tokio::time::timeout(
+        Duration::from_secs(2),
+        next_schedule_result(&mut connection),
+    )
+    .await
+    .expect("No schedule result.");
+
+    // Assert
+    assert_eq!(first["schedule"], "files");
+    assert_eq!(first["job"]["status"], "succeeded");
+    assert_eq!(first["payload"].as_array().map(Vec::len), Some(4));
+}
+
+#[actix_rt::test]
+async fn cron_schedules_run() {
+    // Arrange
+    let schedule = ScheduleSettings {
+        every: None,
+        cron: Some("* * * * * *".to_string()),
+        ..files_every(Duration::ZERO)
+    };
+    let app = spawn_app_with(with_schedule(schedule)).await;
+    let mut connection = app.connect().await;
+    jobs(&mut connection, "subscribe", "files").await;
+
+    // Act
+    let result = tokio::time::timeout(
+        Duration::from_secs(3),
+        next_schedule_result(&mut connection),
+    )
+    .await
+    .expect("No schedule result.");
+
+    // Assert
+    assert_eq!(result["success"], true);
+}
+
+#[actix_rt::test]
+async fn subscribing_to_unknown_schedules_fails() {
+    // Arrange
+    let app = spawn_app_with(with_schedule(files_every(Duration::from_secs(60)))).await;
+    let mut connection = app.connect().await;
+
+    // Act
+    send_text(
+        &mut connection,
+        r#"{"system": "jobs", "task": "subscribe", "payload": {"schedule": "made_up"}}"#,
+    )
+    .await;
+    let result = next_result(&mut connection).await;
+
+    // Assert
+    assert!(!result.success, "Subscribed to an unknown schedule.");
+}
+
+#[actix_rt::test]
+async fn invalid_schedules_are_rejected_on_startup() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.port = 0;
+    configuration.subsystems.jobs.store = None;
+    configuration.subsystems.jobs.schedules = vec![ScheduleSettings {
+        cron: Some("not a cron expression".to_string()),
+        ..files_every(Duration::from_secs(1))
+    }];
+
+    // Act
+    let application = Application::build(configuration);
+
+    // Assert
+    assert!(application.is_err(), "Built with an invalid schedule.");
+}
+
+#[actix_rt::test]
+async fn subscriptions_keep_the_session_active() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        with_schedule(files_every(Duration::from_millis(100)))(c);
+        c.websocket.idle_timeout = Duration::from_millis(300);
+        c.websocket.idle_warning = Duration::from_millis(150);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    jobs(&mut connection, "subscribe", "files").await;
+
+    // Act
+    let mut messages = Vec::new();
+    while messages.len() < 8 {
+        messages.push(next_json(&mut connection).await);
+    }
+
+    // Assert
+    for msg in messages {
+        assert_eq!(msg["type"], "schedule_result", "Unexpected: {:?}", msg);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::helpers::{next_json, next_result, send_text, spawn_app, spawn_app_with, Connection};
+use axum_websockets::message::ResultMessage;
+
+async fn request(
+    connection: &mut Connection,
+    task: &str,
+    payload: serde_json::Value,
+) -> ResultMessage {
+    let message = serde_json::json!({
+        "system": "session",
+        "task": task,
+        "payload": payload,
+    })
+    .to_string();
+    send_text(connection, &message).await;
+    next_result(connection).await
+}
+
+#[actix_rt::test]
+async fn set_values_can_be_read_back() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect().await;
+    let value = serde_json::json!({"path": "tests/examples", "recursive": true});
+    request(
+        &mut connection,
+        "set",
+        serde_json::json!({"key": "cwd", "value": value}),
+    )
+    .await;
+
+    // Act
+    let result = request(&mut connection, "get", serde_json::json!({"key": "cwd"})).await;
+    let all = request(&mut connection, "get", serde_json::Value::Null).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(result.payload, value);
+    assert_eq!(all.payload, serde_json::json!({ "cwd": value }));
+}
+
+#[actix_rt::test]
+async fn values_are_scoped_to_their_session() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut first = app.connect().await;
+    let mut second = app.connect().await;
+    request(
+        &mut first,
+        "set",
+        serde_json::json!({"key": "theme", "value": "dark"}),
+    )
+    .await;
+
+    // Act
+    let result = request(&mut second, "get", serde_json::json!({"key": "theme"})).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert_eq!(result.payload, serde_json::Value::Null);
+}
+
+#[actix_rt::test]
+async fn null_value_removes_the_key() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect().await;
+    request(
+        &mut connection,
+        "set",
+        serde_json::json!({"key": "theme", "value": "dark"}),
+    )
+    .await;
+
+    // Act
+    request(
+        &mut connection,
+        "set",
+        serde_json::json!({"key": "theme", "value": null}),
+    )
+    .await;
+    let all = request(&mut connection, "get", serde_json::Value::Null).await;
+
+    // Assert
+    assert_eq!(all.payload, serde_json::json!({}));
+}
+
+#[actix_rt::test]
+async fn store_is_limited_to_max_keys() {
+    // Arrange
+    let app = spawn_app_with(|c| c.subsystems.session.max_keys = 1).await;
+    let mut connection = app.connect().await;
+    request(
+        &mut connection,
+        "set",
+        serde_json::json!({"key": "a", "value": 1}),
+    )
+    .await;
+
+    // Act
+    let other = request(
+        &mut connection,
+        "set",
+        serde_json::json!({"key": "b", "value": 2}),
+    )
+    .await;
+    let same = request(
+        &mut connection,
+        "set",
+        serde_json::json!({"key": "a", "value": 3}),
+    )
+    .await;
+
+    // Assert
+    assert!(!other.success, "Stored more keys than allowed.");
+    assert!(same.success, "Failed: {:?}", same.payload);
+}
+
+#[actix_rt::test]
+async fn info_reports_session_and_negotiated_parameters() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut connection = app.connect().await;
+    send_text(
+        &mut connection,
+        r#"{"type": "hello", "heartbeat_interval": 500}"#,
+    )
+    .await;
+    let welcome = next_json(&mut connection).await;
+
+    // Act
+    let result = request(&mut connection, "info", serde_json::Value::Null).await;
+
+    // Assert
+    assert!(result.success, "Failed: {:?}", result.payload);
+    assert!(result.payload["id"].is_string());
+    assert_eq!(result.payload["identity"], serde_json::Value::Null);
+    assert_eq!(
+        result.payload["negotiated"]["heartbeat_interval"],
+        welcome["heartbeat_interval"]
+    );
+}
@@ -0,0 +1,109 @@
+use crate::helpers::{send_text, spawn_app, spawn_app_with, Connection};
+use awc::Client;
+use axum_websockets::shutdown::SHUTDOWN_CLOSE_CODE;
+use futures::StreamExt;
+use std::time::Duration;
+
+/// Waits for the close frame, skipping other frames.
+async fn close_code(connection: &mut Connection) -> Option<u16> {
+    loop {
+        match connection.next().await {
+            Some(Ok(awc::ws::Frame::Close(reason))) => return reason.map(|r| u16::from(r.code)),
+            Some(Ok(_)) => {}
+            _ => return None,
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn shutdown_closes_open_sessions() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut first = app.connect().await;
+    let mut second = app.connect_to("/jsonrpc").await;
+
+    // Act
+    app.shutdown.trigger();
+
+    // Assert
+    assert_eq!(close_code(&mut first).await, Some(SHUTDOWN_CLOSE_CODE));
+    assert_eq!(close_code(&mut second).await, Some(SHUTDOWN_CLOSE_CODE));
+    tokio::time::timeout(Duration::from_secs(1), app.server)
+        .await
+        .expect("Server did not stop.")
+        .expect("Server task failed.")
+        .expect("Server failed.");
+}
+
+#[actix_rt::test]
+async fn shutdown_closes_graphql_sessions() {
+    // Arrange
+    let app = spawn_app().await;
+    let (_response, mut connection) = Client::new()
+        .ws(format!("{}/graphql", app.address))
+        .protocols(["graphql-transport-ws"])
+        .connect()
+        .await
+        .expect("Failed to connect to websocket.");
+    send_text(&mut connection, r#"{"type": "connection_init"}"#).await;
+
+    // Act
+    app.shutdown.trigger();
+
+    // Assert
+    assert_eq!(close_code(&mut connection).await, Some(SHUTDOWN_CLOSE_CODE));
+    tokio::time::timeout(Duration::from_secs(1), app.server)
+        .await
+        .expect("Server did not stop.")
+        .expect("Server task failed.")
+        .expect("Server failed.");
+}
+
+#[actix_rt::test]
+async fn shutdown_closes_sessions_with_running_tasks() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.subsystems.pc_usage.sample_duration = Duration::from_secs(10);
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    send_text(
+        &mut connection,
+        r#"{"system": "pc_usage", "task": "cpu_load"}"#,
+    )
+    .await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Act
+    app.shutdown.trigger();
+
+    // Assert
+    assert_eq!(close_code(&mut connection).await, Some(SHUTDOWN_CLOSE_CODE));
+    tokio::time::timeout(Duration::from_secs(1), app.server)
+        .await
+        .expect("Server did not stop.")
+        .expect("Server task failed.")
+        .expect("Server failed.");
+}
+
+#[actix_rt::test]
+async fn stopped_server_refuses_connections() {
+    // Arrange
+    let app = spawn_app().await;
+    app.shutdown.trigger();
+    tokio::time::timeout(Duration::from_secs(1), app.server)
+        .await
+        .expect("Server did not stop.")
+        .expect("Server task failed.")
+        .expect("Server failed.");
+
+    // Act
+    let result = Client::new()
+        .ws(format!("{}/ws", app.address))
+        .connect()
+        .await;
+
+    // Assert
+    assert!(result.is_err(), "Connected to a stopped server.");
+}
@@ -0,0 +1,99 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+use axum_websockets::{message::RequestId, subsystems::WebsocketSystem};
+
+#[actix_rt::test]
+async fn streamed_get_files_ends_with_last_chunk() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = serde_json::json!({
+        "id": 7,
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "tests/examples",
+        "stream": true
+    })
+    .to_string();
+
+    // Act
+    let results = app
+        .get_results(&message, |r| r.chunk.is_none_or(|c| c.last))
+        .await;
+
+    // Assert
+    let mut files = Vec::new();
+    for (i, result) in results.into_iter().enumerate() {
+        assert!(result.success, "Call was not successful.");
+        assert_eq!(result.id, Some(RequestId::Number(7)));
+        assert_eq!(result.system, Some(WebsocketSystem::PythonRepo));
+        let chunk = result.chunk.expect("Missing chunk information.");
+        assert_eq!(chunk.index, i);
+        files.extend(result.payload.as_array().cloned().unwrap_or_default());
+    }
+    assert_eq!(files.len(), 4);
+}
+
+#[actix_rt::test]
+async fn streamed_task_without_streaming_support_sends_one_chunk() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = serde_json::json!({
+        "id": "cpu",
+        "system": "pc_usage",
+        "task": "cpu_load",
+        "stream": true
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(result.success, "Call was not successful.");
+    assert_eq!(result.id, Some(RequestId::String("cpu".into())));
+    let chunk = result.chunk.expect("Missing chunk information.");
+    assert_eq!((chunk.index, chunk.last), (0, true));
+}
+
+#[actix_rt::test]
+async fn streamed_get_files_error_is_sent_as_last_chunk() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "tests/some_incorrect_path",
+        "stream": true
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(!result.success, "Call should not success.");
+    assert!(result.chunk.is_some_and(|c| c.last), "Expected last chunk.");
+}
+
+#[actix_rt::test]
+async fn streamed_chunk_size_is_configurable() {
+    // Arrange
+    let app = spawn_app_with(|c| c.subsystems.python_repo.chunk_size = 1).await;
+    let message = serde_json::json!({
+        "system": "python_repo",
+        "task": "get_files",
+        "payload": "tests/examples",
+        "stream": true
+    })
+    .to_string();
+
+    // Act
+    let results = app
+        .get_results(&message, |r| r.chunk.is_none_or(|c| c.last))
+        .await;
+
+    // Assert
+    assert_eq!(results.len(), 4);
+    for result in results {
+        assert_eq!(result.payload.as_array().map(Vec::len), Some(1));
+    }
+}
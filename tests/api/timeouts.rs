@@ -0,0 +1,92 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+use axum_websockets::{
+    message::{ErrorCode, ErrorPayload, RequestId, ResultMessage},
+    subsystems::WebsocketSystem,
+};
+use std::time::Duration;
+
+fn assert_timed_out(result: ResultMessage) {
+    assert!(!result.success, "Call should not success.");
+    let error = serde_json::from_value::<ErrorPayload>(result.payload)
+        .expect("Failed to deserialize error payload.");
+    assert_eq!(error.code, ErrorCode::Timeout);
+}
+
+#[actix_rt::test]
+async fn task_over_client_deadline_receives_timeout_error() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = serde_json::json!({
+        "id": 1,
+        "system": "pc_usage",
+        "task": "cpu_load",
+        "timeout": 20
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert_eq!(result.id, Some(RequestId::Number(1)));
+    assert_timed_out(result);
+}
+
+#[actix_rt::test]
+async fn task_over_server_timeout_receives_timeout_error() {
+    // Arrange
+    let app = spawn_app_with(|c| c.websocket.task_timeout = Duration::from_millis(20)).await;
+    let message = serde_json::json!({
+        "system": "pc_usage",
+        "task": "cpu_load",
+        "timeout": 60000
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert_timed_out(result);
+}
+
+#[actix_rt::test]
+async fn subsystem_timeout_overrides_server_default() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.task_timeout = Duration::from_secs(60);
+        c.websocket
+            .subsystem_task_timeouts
+            .insert(WebsocketSystem::PcUsage, Duration::from_millis(20));
+    })
+    .await;
+    let message = serde_json::json!({
+        "system": "pc_usage",
+        "task": "cpu_load"
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert_timed_out(result);
+}
+
+#[actix_rt::test]
+async fn task_within_deadline_succeeds() {
+    // Arrange
+    let app = spawn_app().await;
+    let message = serde_json::json!({
+        "system": "pc_usage",
+        "task": "cpu_load",
+        "timeout": 5000
+    })
+    .to_string();
+
+    // Act
+    let result = app.get_first_result(&message).await;
+
+    // Assert
+    assert!(result.success, "Call was not successful.");
+}
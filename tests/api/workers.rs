@@ -0,0 +1,134 @@
+use crate::helpers::{next_result, send_text, spawn_app_with, spawn_app_with_layers};
+use axum_websockets::service::{TaskLayers, TaskRequest};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tower::{layer::layer_fn, util::MapRequest};
+
+/// Decrements the count of running workers when the service of a worker is dropped.
+struct RunningWorker(Arc<AtomicUsize>);
+
+impl Drop for RunningWorker {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Layers counting the started and running workers, each worker wrapping its service once.
+fn worker_counter() -> (Arc<AtomicUsize>, Arc<AtomicUsize>, TaskLayers) {
+    let started = Arc::new(AtomicUsize::new(0));
+    let running = Arc::new(AtomicUsize::new(0));
+    let layer = {
+        let (started, running) = (started.clone(), running.clone());
+        layer_fn(move |inner| {
+            started.fetch_add(1, Ordering::SeqCst);
+            running.fetch_add(1, Ordering::SeqCst);
+            let worker = Arc::new(RunningWorker(running.clone()));
+            MapRequest::new(inner, move |req: TaskRequest| {
+                let _ = &worker;
+                req
+            })
+        })
+    };
+    (started, running, TaskLayers::default().layer(layer))
+}
+
+#[actix_rt::test]
+async fn requests_succeed_after_worker_shuts_down() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.worker_idle_timeout = Duration::from_millis(30);
+        // Keep the session alive without answering pings
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    let request = r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples"}"#;
+
+    for _ in 0..3 {
+        // Act
+        send_text(&mut connection, request).await;
+        let result = next_result(&mut connection).await;
+
+        // Assert
+        assert!(result.success, "Failed: {:?}", result.payload);
+        assert_eq!(result.payload.as_array().map(Vec::len), Some(4));
+        // Give the worker time to shut down
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[actix_rt::test]
+async fn queued_tasks_complete_when_worker_shuts_down() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.websocket.worker_idle_timeout = Duration::from_millis(1);
+        c.websocket.client_timeout = Duration::from_secs(10);
+    })
+    .await;
+    let mut connection = app.connect().await;
+    let batch = (0..10)
+        .map(|i| {
+            serde_json::json!({
+                "id": i,
+                "system": if i % 2 == 0 { "python_repo" } else { "pc_usage" },
+                "task": if i % 2 == 0 { "get_files" } else { "cpu_load" },
+                "payload": "tests/examples"
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Act
+    send_text(
+        &mut connection,
+        &serde_json::Value::Array(batch).to_string(),
+    )
+    .await;
+    let mut results = Vec::new();
+    for _ in 0..10 {
+        results.push(next_result(&mut connection).await);
+    }
+
+    // Assert
+    assert!(results.iter().all(|result| result.success));
+}
+
+#[actix_rt::test]
+async fn workers_start_on_demand_and_stop_when_unused() {
+    // Arrange
+    let (started, running, layers) = worker_counter();
+    let app = spawn_app_with_layers(
+        |c| {
+            c.websocket.worker_idle_timeout = Duration::from_millis(100);
+            c.websocket.client_timeout = Duration::from_secs(10);
+        },
+        layers,
+    )
+    .await;
+    let mut connection = app.connect().await;
+    let request = r#"{"system": "python_repo", "task": "get_files", "payload": "tests/examples"}"#;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(started.load(Ordering::SeqCst), 0, "Worker started eagerly.");
+
+    // Act
+    for _ in 0..2 {
+        send_text(&mut connection, request).await;
+        let result = next_result(&mut connection).await;
+        assert!(result.success, "Failed: {:?}", result.payload);
+    }
+    let running_after_requests = running.load(Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // Assert
+    assert_eq!(started.load(Ordering::SeqCst), 1, "Worker was not reused.");
+    assert_eq!(running_after_requests, 1);
+    assert_eq!(
+        running.load(Ordering::SeqCst),
+        0,
+        "Unused worker kept running."
+    );
+}